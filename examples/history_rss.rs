@@ -0,0 +1,115 @@
+
+//! Shows the peak memory use of the binary on a big input, for the
+//! different backends. Linux only, since it reads /proc for the RSS.
+//!
+//!     cargo build --release
+//!     cargo run --release --example history_rss -- [rows] [memory|retention|spill]
+//!
+//! It runs every backend unless one is given, on 100M rows by default.
+//!
+//! The rows are written to a CSV file in the temp dir, mostly deposits, with
+//! a withdrawal every 10 rows and a dispute of a recent deposit every 100.
+//! Then the kraken binary built next to this example processes the file
+//! with the flags of the backend, like any other input, while its peak RSS
+//! is read from /proc every 10ms.
+//!
+//! On 100M rows (a 3GB CSV), on one core of a 6GB VM:
+//!
+//!     memory     peak rss 5574 MB, 142.9s
+//!     retention  peak rss   28 MB, 105.5s
+//!     spill      peak rss  258 MB, 120.0s
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::process::{Command, ExitStatus, Stdio};
+use std::{env, thread, time::Duration, time::Instant};
+
+/// Peak RSS of a running process in kB, None once it's gone
+fn peak_rss(pid: u32) -> Option<u64> {
+	let status = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+	status.lines()
+		.find(|l| l.starts_with("VmHWM:"))
+		.and_then(|l| l.split_whitespace().nth(1))
+		.and_then(|kb| kb.parse().ok())
+}
+
+fn write_input(path: &Path, rows: u32) {
+	let mut out = BufWriter::new(File::create(path).expect("can't create input file"));
+	writeln!(out, "type,client,tx,amount").unwrap();
+	for i in 0..rows {
+		let client = i % 65536;
+		if i % 100 == 99 {
+			writeln!(out, "dispute,{},{},", (i - 50) % 65536, i - 50).unwrap();
+		} else if i % 10 == 9 {
+			writeln!(out, "withdrawal,{},{},0.0001", client, i).unwrap();
+		} else {
+			writeln!(out, "deposit,{},{},{}.{:04}", client, i, i % 100_000 / 10_000, i % 10_000).unwrap();
+		}
+	}
+	out.flush().expect("can't write input file");
+}
+
+/// Run the binary on the input with the flags of a backend, and return
+/// how long it took and its peak RSS in kB
+fn measure(binary: &Path, input: &Path, spill: &Path, backend: &str) -> (ExitStatus, Duration, u64) {
+	let mut command = Command::new(binary);
+	command.arg(input);
+	match backend {
+		"memory" => {},
+		"retention" => {
+			command.args(["--retention", "1000000"]);
+		},
+		"spill" => {
+			command.arg("--spill").arg(spill);
+		},
+		other => panic!("unknown backend {}", other),
+	}
+	println!("{:?}", command);
+
+	let start = Instant::now();
+	let mut child = command.stdout(Stdio::null()).spawn().expect("can't run the binary");
+	let mut peak = 0;
+	let status = loop {
+		if let Some(rss) = peak_rss(child.id()) {
+			peak = peak.max(rss);
+		}
+		if let Some(status) = child.try_wait().expect("can't wait for the binary") {
+			break status;
+		}
+		thread::sleep(Duration::from_millis(10));
+	};
+	let _ = fs::remove_file(spill);
+	(status, start.elapsed(), peak)
+}
+
+fn main() {
+	let rows = env::args().nth(1).map_or(100_000_000, |r| r.parse().expect("invalid row count"));
+	let backends = match env::args().nth(2) {
+		Some(backend) => vec![backend],
+		None => vec!["memory".to_string(), "retention".to_string(), "spill".to_string()],
+	};
+
+	// target/<profile>/examples/history_rss -> target/<profile>/kraken
+	let exe = env::current_exe().expect("no current exe");
+	let binary = exe.parent().and_then(Path::parent).expect("no target dir").join("kraken");
+	assert!(binary.exists(), "{} not found, build it first", binary.display());
+
+	let tmp = env::temp_dir();
+	let input = tmp.join(format!("history_rss-{}.csv", std::process::id()));
+	let spill = tmp.join(format!("history_rss-{}.spill", std::process::id()));
+	write_input(&input, rows);
+
+	for backend in &backends {
+		let (status, elapsed, peak) = measure(&binary, &input, &spill, backend);
+		if status.success() {
+			println!("{}: rows: {}, time: {:.1?}, peak rss: {} MB", backend, rows, elapsed, peak / 1024);
+		} else {
+			// most likely killed for running out of memory
+			println!("{}: rows: {}, failed after {:.1?}: {}, peak rss: {} MB",
+				backend, rows, elapsed, status, peak / 1024,
+			);
+		}
+	}
+	let _ = fs::remove_file(&input);
+}
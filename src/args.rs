@@ -0,0 +1,397 @@
+//! Command line parsing
+
+use std::env;
+
+use rust_decimal::Decimal;
+
+use kraken::{
+	AccountOrder, Backend, ClientId, DisputePolicy, DuplicatePolicy, LockedPolicy, NegativeBalancePolicy,
+	OwnershipPolicy, Precision, Rounding, Store,
+};
+
+use crate::{fail, EXIT_USAGE};
+
+const USAGE: &str = "\
+usage: kraken [process] <input>... [options]
+       kraken validate <input>... [options]
+       kraken replay <state dir> [options]
+       kraken inspect <client> [<input>...] [options]
+       kraken statement <client> <input>... [options]
+       kraken export-sql <tables dir>
+       kraken serve <host:port|unix:path> [options]
+       kraken http <host:port> [options]
+       kraken --help";
+
+const HELP: &str = "
+Commands:
+  process      apply the inputs and print the accounts, the default
+  validate     check that the inputs parse, without applying anything
+  replay       print the accounts saved in a state dir
+  inspect      print one account and its deposits and withdrawals
+  statement    print every operation on one account with the running
+               balance
+  export-sql   print the accounts and txs of a --tables dir as SQL
+               statements, to load them into a database
+  serve        apply txs sent over a socket, see the docs of serve mode
+  http         serve a JSON API over HTTP
+
+Inputs are CSV files, or - for stdin. Files ending in .gz or .zst are
+decompressed by running gzip -dc or zstd -dc, which have to be
+installed and on the PATH; nothing else needs them.
+
+Input and output:
+  --input-format csv|jsonl      format of the inputs, csv by default
+  --output-format csv|jsonl|json
+                                format of the accounts or statement,
+                                csv by default
+  --merge                       merge the inputs by txid instead of
+                                reading them one after the other
+  --rejects <path>              write the rows that were not applied
+  --sort client|total|locked    order of the accounts
+  --decimals <n>                decimals of the balances, 4 by default
+  --rounding half-even|half-up  rounding of the balances
+
+Engine policies:
+  --locked-policy default|frozen|permissive
+                                what locked accounts still allow; by
+                                default only resolves and chargebacks
+  --ownership reject|apply-to-owner
+                                disputes of another client's tx
+  --duplicates reject|idempotent
+                                reused txids
+  --disputes as-deposit|by-type how disputed withdrawals are handled
+  --negative reject|allow       whether disputes may make funds negative
+  --credit-limit <amount>       let disputes make funds negative up to
+                                this amount
+  --client-credit-limit <client>=<amount>
+                                the credit limit of one client
+
+Storage:
+  --state <dir>                 continue from the state saved in dir
+                                and save to it
+  --snapshot-interval <txs>     txs between snapshots of the --state
+  --tables <dir>                keep the accounts and txs in files in dir,
+                                to query them after the run
+  --retention <txs>             forget txs this many txs later
+  --spill <path>                keep old txs in this file
+  --threads <n>                 process on n threads, which keeps
+                                every txid in memory, so not with
+                                --retention or --spill
+
+Checks:
+  --trial-balance               keep a double-entry journal and check
+                                after every input that it sums to zero
+                                and matches the accounts
+  --paranoid                    check the invariants of the accounts a tx
+                                touched after every tx, and of all accounts
+                                after every input; stop at the first
+                                violation
+
+Exit codes:
+  1  reading or writing failed
+  2  bad command line
+  3  the input couldn't be parsed, or didn't validate
+  4  an internal invariant was violated
+";
+
+/// Print the usage and exit
+pub fn usage() -> ! {
+	fail(EXIT_USAGE, USAGE)
+}
+
+/// The formats rows can be read in
+#[derive(Debug, Clone, Copy)]
+pub enum InputFormat {
+	Csv,
+	/// A JSON object per line, with the same fields as the CSV columns
+	Jsonl,
+}
+
+/// The formats the accounts can be written in
+#[derive(Debug, Clone, Copy)]
+pub enum OutputFormat {
+	Csv,
+	/// A JSON object per line
+	Jsonl,
+	/// A JSON array
+	Json,
+}
+
+/// What the binary is asked to do
+pub enum Mode {
+	/// Process input files and print the accounts
+	Process { inputs: Vec<String> },
+	/// Check input files without applying them
+	Validate { inputs: Vec<String> },
+	/// Print the accounts of a state dir
+	Replay { dir: String },
+	/// Process input files and print one client
+	Inspect { client: ClientId, inputs: Vec<String> },
+	/// Process input files and print the statement of one client
+	Statement { client: ClientId, inputs: Vec<String> },
+	/// Print the tables of a --tables dir as SQL
+	ExportSql { dir: String },
+	/// Serve a store over a socket
+	Serve { addr: String },
+	/// Serve a store over HTTP
+	Http { addr: String },
+}
+
+pub struct Args {
+	pub mode: Mode,
+	pub rejects: Option<String>,
+	pub sort: AccountOrder,
+	pub precision: Precision,
+	pub retention: Option<u32>,
+	pub spill: Option<String>,
+	pub threads: usize,
+	pub input_format: InputFormat,
+	pub output_format: OutputFormat,
+	pub merge: bool,
+	pub state: Option<String>,
+	pub snapshot_interval: Option<u64>,
+	pub tables: Option<String>,
+	pub paranoid: bool,
+	trial_balance: bool,
+	locked_policy: LockedPolicy,
+	ownership_policy: OwnershipPolicy,
+	duplicate_policy: DuplicatePolicy,
+	dispute_policy: DisputePolicy,
+	negative_policy: NegativeBalancePolicy,
+	credit_limits: Vec<(ClientId, Decimal)>,
+}
+
+impl Args {
+	/// Set up a store with the policies that were asked for
+	pub fn configure<B: Backend>(&self, store: Store<B>) -> Store<B> {
+		let store = store
+			.with_precision(self.precision)
+			.with_locked_policy(self.locked_policy)
+			.with_ownership_policy(self.ownership_policy)
+			.with_duplicate_policy(self.duplicate_policy)
+			.with_dispute_policy(self.dispute_policy)
+			.with_negative_policy(self.negative_policy);
+		let store = if self.trial_balance { store.with_journal() } else { store };
+		let store = if self.paranoid { store.with_disputed_sums() } else { store };
+		self.credit_limits.iter().fold(store, |store, &(client, limit)| store.with_credit_limit(client, limit))
+	}
+}
+
+pub fn parse_args() -> Args {
+	let mut positional = Vec::new();
+	let mut rejects = None;
+	let mut sort = AccountOrder::default();
+	let mut precision = Precision::default();
+	let mut retention = None;
+	let mut spill = None;
+	let mut threads = 1;
+	let mut input_format = InputFormat::Csv;
+	let mut output_format = OutputFormat::Csv;
+	let mut merge = false;
+	let mut state = None;
+	let mut snapshot_interval = None;
+	let mut tables = None;
+	let mut trial_balance = false;
+	let mut paranoid = false;
+	let mut locked_policy = LockedPolicy::default();
+	let mut ownership_policy = OwnershipPolicy::default();
+	let mut duplicate_policy = DuplicatePolicy::default();
+	let mut dispute_policy = DisputePolicy::default();
+	let mut negative_policy = None;
+	let mut credit_limit = None;
+	let mut credit_limits = Vec::new();
+
+	let mut args = env::args().skip(1);
+	while let Some(arg) = args.next() {
+		match arg.as_str() {
+			"-h" | "--help" => {
+				println!("{}\n{}", USAGE, HELP);
+				std::process::exit(0);
+			},
+			"--merge" => merge = true,
+			"--trial-balance" => trial_balance = true,
+			"--paranoid" => paranoid = true,
+			"--rejects" => rejects = Some(args.next().unwrap_or_else(|| usage())),
+			"--sort" => sort = match args.next().as_deref() {
+				Some("client") => AccountOrder::Client,
+				Some("total") => AccountOrder::Total,
+				Some("locked") => AccountOrder::LockedFirst,
+				_ => usage(),
+			},
+			"--decimals" => precision.decimals = match args.next().map(|n| n.parse()) {
+				Some(Ok(n)) if n <= 28 => n,
+				_ => usage(),
+			},
+			"--rounding" => precision.rounding = match args.next().as_deref() {
+				Some("half-even") => Rounding::HalfEven,
+				Some("half-up") => Rounding::HalfUp,
+				_ => usage(),
+			},
+			"--retention" => retention = match args.next().map(|n| n.parse()) {
+				Some(Ok(n)) if n > 0 => Some(n),
+				_ => usage(),
+			},
+			"--spill" => spill = Some(args.next().unwrap_or_else(|| usage())),
+			"--threads" => threads = match args.next().map(|n| n.parse()) {
+				Some(Ok(n)) if n > 0 => n,
+				_ => usage(),
+			},
+			"--input-format" => input_format = match args.next().as_deref() {
+				Some("csv") => InputFormat::Csv,
+				Some("jsonl") => InputFormat::Jsonl,
+				_ => usage(),
+			},
+			"--output-format" => output_format = match args.next().as_deref() {
+				Some("csv") => OutputFormat::Csv,
+				Some("jsonl") => OutputFormat::Jsonl,
+				Some("json") => OutputFormat::Json,
+				_ => usage(),
+			},
+			"--state" => state = Some(args.next().unwrap_or_else(|| usage())),
+			"--snapshot-interval" => snapshot_interval = match args.next().map(|n| n.parse()) {
+				Some(Ok(n)) if n > 0 => Some(n),
+				_ => usage(),
+			},
+			"--tables" => tables = Some(args.next().unwrap_or_else(|| usage())),
+			"--locked-policy" => locked_policy = match args.next().as_deref() {
+				Some("default") => LockedPolicy::default(),
+				Some("frozen") => LockedPolicy::frozen(),
+				Some("permissive") => LockedPolicy::permissive(),
+				_ => usage(),
+			},
+			"--ownership" => ownership_policy = match args.next().as_deref() {
+				Some("reject") => OwnershipPolicy::Reject,
+				Some("apply-to-owner") => OwnershipPolicy::ApplyToOwner,
+				_ => usage(),
+			},
+			"--duplicates" => duplicate_policy = match args.next().as_deref() {
+				Some("reject") => DuplicatePolicy::Reject,
+				Some("idempotent") => DuplicatePolicy::Idempotent,
+				_ => usage(),
+			},
+			"--disputes" => dispute_policy = match args.next().as_deref() {
+				Some("as-deposit") => DisputePolicy::AsDeposit,
+				Some("by-type") => DisputePolicy::ByTxType,
+				_ => usage(),
+			},
+			"--negative" => negative_policy = match args.next().as_deref() {
+				Some("reject") => Some(NegativeBalancePolicy::Reject),
+				Some("allow") => Some(NegativeBalancePolicy::AllowNegative),
+				_ => usage(),
+			},
+			"--credit-limit" => credit_limit = match args.next().map(|n| n.parse::<Decimal>()) {
+				Some(Ok(n)) if !n.is_sign_negative() => Some(n),
+				_ => usage(),
+			},
+			"--client-credit-limit" => {
+				let arg = args.next().unwrap_or_else(|| usage());
+				let (client, limit) = arg.split_once('=').unwrap_or_else(|| usage());
+				match (client.parse(), limit.parse::<Decimal>()) {
+					(Ok(client), Ok(limit)) if !limit.is_sign_negative() => credit_limits.push((client, limit)),
+					_ => usage(),
+				}
+			},
+			_ if arg == "-" || !arg.starts_with('-') => positional.push(arg),
+			_ => usage(),
+		}
+	}
+
+	// credit limits only mean something with the policy that uses them
+	let negative_policy = match (negative_policy, credit_limit) {
+		(None, Some(default)) => NegativeBalancePolicy::CreditLimit { default },
+		(None, None) if !credit_limits.is_empty() => NegativeBalancePolicy::CreditLimit { default: Decimal::ZERO },
+		(Some(_), Some(_)) => usage(),
+		(policy, _) => policy.unwrap_or_default(),
+	};
+
+	let mut positional = positional.into_iter();
+	let command = positional.next().unwrap_or_else(|| usage());
+	let rest = positional.collect::<Vec<_>>();
+	let one = |rest: Vec<String>| match rest.len() {
+		1 => rest.into_iter().next().unwrap(),
+		_ => usage(),
+	};
+	let mode = match command.as_str() {
+		"help" => {
+			println!("{}\n{}", USAGE, HELP);
+			std::process::exit(0);
+		},
+		"process" if !rest.is_empty() => Mode::Process { inputs: rest },
+		"validate" if !rest.is_empty() => Mode::Validate { inputs: rest },
+		"replay" => Mode::Replay { dir: one(rest) },
+		"inspect" => {
+			let mut rest = rest.into_iter();
+			let client = rest.next().and_then(|c| c.parse().ok()).unwrap_or_else(|| usage());
+			let inputs = rest.collect::<Vec<_>>();
+			if inputs.is_empty() && state.is_none() {
+				usage();
+			}
+			Mode::Inspect { client, inputs }
+		},
+		"statement" => {
+			let mut rest = rest.into_iter();
+			let client = rest.next().and_then(|c| c.parse().ok()).unwrap_or_else(|| usage());
+			let inputs = rest.collect::<Vec<_>>();
+			if inputs.is_empty() {
+				usage();
+			}
+			// the event log only covers what was applied in this run
+			if state.is_some() {
+				fail(EXIT_USAGE, "statement can't be combined with --state");
+			}
+			Mode::Statement { client, inputs }
+		},
+		"export-sql" => Mode::ExportSql { dir: one(rest) },
+		"serve" => Mode::Serve { addr: one(rest) },
+		"http" => Mode::Http { addr: one(rest) },
+		"process" | "validate" => usage(),
+		// without a command, all arguments are inputs
+		_ => Mode::Process { inputs: std::iter::once(command).chain(rest).collect() },
+	};
+
+	// snapshots are only taken of a saved state
+	if snapshot_interval.is_some() && state.is_none() {
+		fail(EXIT_USAGE, "--snapshot-interval needs --state");
+	}
+	// the persistent store only keeps a single store in memory
+	if state.is_some() && (threads > 1 || retention.is_some() || spill.is_some()) {
+		fail(EXIT_USAGE, "--state can't be combined with --threads, --retention or --spill");
+	}
+	// the tables are written by a single store, and hold every tx
+	if tables.is_some() && (state.is_some() || threads > 1 || retention.is_some() || spill.is_some()) {
+		fail(EXIT_USAGE, "--tables can't be combined with --state, --threads, --retention or --spill");
+	}
+	// the shards apply txs in the background
+	if threads > 1 && (trial_balance || paranoid) {
+		fail(EXIT_USAGE, "--trial-balance and --paranoid can't be combined with --threads");
+	}
+	// every shard would count its own txs for the retention window, and
+	// the sharded store keeps the owner of every txid in memory anyway
+	if threads > 1 && (retention.is_some() || spill.is_some()) {
+		fail(EXIT_USAGE, "--retention and --spill can't be combined with --threads");
+	}
+
+	Args {
+		mode,
+		rejects,
+		sort,
+		precision,
+		retention,
+		spill,
+		threads,
+		input_format,
+		output_format,
+		merge,
+		state,
+		snapshot_interval,
+		tables,
+		trial_balance,
+		paranoid,
+		locked_policy,
+		ownership_policy,
+		duplicate_policy,
+		dispute_policy,
+		negative_policy,
+		credit_limits,
+	}
+}
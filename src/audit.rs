@@ -0,0 +1,271 @@
+//! A self-check of the `Store`, for finding bugs rather than bad input.
+//!
+//! The handlers keep these invariants on every accepted tx:
+//!
+//! - the total of every account, `available + held`, fits a `Decimal`
+//! - held funds are never negative
+//! - the held funds of an account are the sum of the amounts of its
+//!   disputed txs, provisional credits included
+//!
+//! The handlers refuse a tx that would overflow a balance, but the audit
+//! doesn't count on that: it adds up the totals and the disputed amounts
+//! with checked arithmetic, and reports a sum that overflows as a
+//! violation rather than panicking.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use rust_decimal::Decimal;
+
+use crate::backend::Backend;
+use crate::store::{Account, ClientId, DisputeState, Store, Transaction};
+
+/// An invariant that doesn't hold
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+	/// `available + held` doesn't fit a `Decimal`
+	TotalOverflow { account: Account },
+	NegativeHeld { account: Account },
+	/// The held funds aren't the sum of the disputed txs
+	HeldMismatch { account: Account, disputed: Decimal },
+	/// The disputed txs of a client sum up to more than fits a `Decimal`
+	DisputedOverflow { client: ClientId },
+}
+
+impl Violation {
+	/// The client whose account is off
+	pub fn client(&self) -> ClientId {
+		match *self {
+			Violation::TotalOverflow { account }
+			| Violation::NegativeHeld { account }
+			| Violation::HeldMismatch { account, .. } => account.id,
+			Violation::DisputedOverflow { client } => client,
+		}
+	}
+}
+
+impl fmt::Display for Violation {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Violation::TotalOverflow { account } => write!(
+				f, "client {}: total of available {} and held {} overflows",
+				account.id, account.available, account.held,
+			),
+			Violation::NegativeHeld { account } => write!(
+				f, "client {}: held {} is negative", account.id, account.held,
+			),
+			Violation::HeldMismatch { account, disputed } => write!(
+				f, "client {}: held {} isn't the sum of the disputed txs, {}",
+				account.id, account.held, disputed,
+			),
+			Violation::DisputedOverflow { client } => write!(
+				f, "client {}: the disputed txs sum up to more than fits a decimal", client,
+			),
+		}
+	}
+}
+
+/// Check the invariants of an account, given the sum of its disputed txs
+/// if that's known, None inside if the sum overflowed
+fn check_account(account: Account, disputed: Option<Option<Decimal>>, violations: &mut Vec<Violation>) {
+	if account.available.checked_add(account.held).is_none() {
+		violations.push(Violation::TotalOverflow { account });
+	}
+	if account.held.is_sign_negative() && !account.held.is_zero() {
+		violations.push(Violation::NegativeHeld { account });
+	}
+	match disputed {
+		Some(Some(disputed)) if disputed != account.held => {
+			violations.push(Violation::HeldMismatch { account, disputed })
+		},
+		Some(None) => violations.push(Violation::DisputedOverflow { client: account.id }),
+		_ => {},
+	}
+}
+
+impl<B: Backend> Store<B> {
+	/// Check the invariants of all accounts, ordered by client. This goes
+	/// through the whole history. The held funds can't be checked when the
+	/// backend forgets txs, as it doesn't know all disputed txs anymore.
+	pub fn audit(&self) -> Vec<Violation> {
+		let backend = self.backend();
+		let mut disputed = BTreeMap::<ClientId, Option<Decimal>>::new();
+		if !backend.forgets_txs() {
+			for tx in backend.txs().filter(|tx| tx.dispute_state == DisputeState::Disputed) {
+				let sum = disputed.entry(tx.client).or_insert(Some(Decimal::ZERO));
+				*sum = sum.and_then(|sum| sum.checked_add(tx.amount));
+			}
+		}
+
+		let mut violations = Vec::new();
+		for account in backend.accounts() {
+			let sum = match backend.forgets_txs() {
+				true => None,
+				false => Some(disputed.remove(&account.id).unwrap_or(Some(Decimal::ZERO))),
+			};
+			check_account(account, sum, &mut violations);
+		}
+		// disputed txs of clients without an account
+		for (client, sum) in disputed {
+			let account = Account::new(client);
+			violations.push(match sum {
+				Some(disputed) => Violation::HeldMismatch { account, disputed },
+				None => Violation::DisputedOverflow { client },
+			});
+		}
+		violations.sort_by_key(Violation::client);
+		violations
+	}
+
+	/// Check the invariants of one account. The held funds are checked
+	/// against the sums kept by `with_disputed_sums`, without those this
+	/// goes through the whole history like `audit`.
+	pub fn audit_client(&self, client: ClientId) -> Vec<Violation> {
+		let backend = self.backend();
+		let disputed = match self.disputed_sums() {
+			Some(sums) => Some(sums.get(&client).copied().unwrap_or(Some(Decimal::ZERO))),
+			None if backend.forgets_txs() => None,
+			None => Some(backend.txs()
+				.filter(|tx| tx.client == client && tx.dispute_state == DisputeState::Disputed)
+				.try_fold(Decimal::ZERO, |sum, tx| sum.checked_add(tx.amount))),
+		};
+		let account = backend.get_account(client).unwrap_or_else(|| Account::new(client));
+		let mut violations = Vec::new();
+		check_account(account, disputed, &mut violations);
+		violations
+	}
+
+	/// Check the invariants of the accounts a tx may have changed: its
+	/// client's, and that of the owner of the tx it refers to
+	pub fn audit_tx(&self, tx: &Transaction) -> Vec<Violation> {
+		let mut violations = self.audit_client(tx.client());
+		let owner = match tx {
+			Transaction::Deposit { .. } | Transaction::Withdrawal { .. } => None,
+			_ => self.get_transaction(tx.txid()).map(|record| record.client),
+		};
+		if let Some(owner) = owner.filter(|&owner| owner != tx.client()) {
+			violations.extend(self.audit_client(owner));
+			violations.sort_by_key(Violation::client);
+		}
+		violations
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::backend::{AccountRepository, TxRepository};
+	use crate::compact::CompactBackend;
+	use crate::store::{DisputePolicy, OwnershipPolicy, TxRecord, TxType};
+
+	fn d(s: &str) -> Decimal {
+		s.parse().expect("invalid decimal")
+	}
+
+	#[test]
+	fn consistent() {
+		let mut store = Store::new().with_dispute_policy(DisputePolicy::ByTxType);
+		let txs = [
+			Transaction::Deposit { txid: 1, client: 1, amount: d("10") },
+			Transaction::Withdrawal { txid: 2, client: 1, amount: d("4") },
+			Transaction::Deposit { txid: 3, client: 2, amount: d("5") },
+			Transaction::Dispute { client: 1, txid: 1 },
+			Transaction::Dispute { client: 1, txid: 2 },
+			Transaction::Dispute { client: 2, txid: 3 },
+			Transaction::Chargeback { client: 2, txid: 3 },
+		];
+		for &tx in &txs {
+			let _ = store.apply(tx);
+			assert_eq!(store.audit(), [], "after {:?}", tx);
+			assert_eq!(store.audit_tx(&tx), [], "after {:?}", tx);
+		}
+	}
+
+	#[test]
+	fn disputed_sums() {
+		let mut store = Store::new()
+			.with_ownership_policy(OwnershipPolicy::ApplyToOwner)
+			.with_disputed_sums();
+		let txs = [
+			Transaction::Deposit { txid: 1, client: 1, amount: d("10") },
+			Transaction::Deposit { txid: 2, client: 1, amount: d("2") },
+			Transaction::Dispute { client: 2, txid: 1 },
+			Transaction::Dispute { client: 1, txid: 2 },
+			Transaction::Resolve { client: 1, txid: 2 },
+		];
+		for &tx in &txs {
+			store.apply(tx).unwrap();
+			assert_eq!(store.audit_tx(&tx), [], "after {:?}", tx);
+		}
+		assert_eq!(store.disputed_sums().unwrap()[&1], Some(d("10")));
+		store.apply(Transaction::Chargeback { client: 1, txid: 1 }).unwrap();
+		assert_eq!(store.disputed_sums().unwrap()[&1], Some(d("0")));
+	}
+
+	#[test]
+	fn client_violations() {
+		let mut backend = crate::backend::MemoryBackend::default();
+		backend.insert_account(Account { held: d("2"), ..Account::new(1) }).unwrap();
+		let mut store = Store::with_backend(backend).with_disputed_sums();
+		store.handle_deposit(1, 1, d("3")).unwrap();
+		store.handle_dispute(1, 1).unwrap();
+
+		let account = Account { available: d("0"), held: d("5"), ..Account::new(1) };
+		let expected = [Violation::HeldMismatch { account, disputed: d("3") }];
+		assert_eq!(store.audit_client(1), expected);
+		assert_eq!(store.audit_tx(&Transaction::Dispute { client: 2, txid: 1 }), expected);
+		assert_eq!(store.audit_client(2), []);
+	}
+
+	#[test]
+	fn violations() {
+		let mut backend = crate::backend::MemoryBackend::default();
+		let account = |id, available: &str, held: &str| Account {
+			id,
+			available: d(available),
+			held: d(held),
+			locked: false,
+		};
+		let disputed = |txid, client, amount: &str| TxRecord {
+			txid,
+			tp: TxType::Deposit,
+			client,
+			amount: d(amount),
+			dispute_state: DisputeState::Disputed,
+		};
+		backend.insert_account(account(1, "1", "2")).unwrap();
+		backend.insert_tx(disputed(1, 1, "1.5")).unwrap();
+		backend.insert_account(account(2, "0", "-1")).unwrap();
+		backend.insert_account(account(3, "79228162514264337593543950335", "1")).unwrap();
+		backend.insert_tx(disputed(2, 3, "1")).unwrap();
+		backend.insert_tx(disputed(3, 4, "3")).unwrap();
+		backend.insert_account(account(5, "0", "0")).unwrap();
+		backend.insert_tx(disputed(4, 5, "79228162514264337593543950335")).unwrap();
+		backend.insert_tx(disputed(5, 5, "1")).unwrap();
+
+		let store = Store::with_backend(backend);
+		assert_eq!(store.audit(), [
+			Violation::HeldMismatch { account: account(1, "1", "2"), disputed: d("1.5") },
+			Violation::NegativeHeld { account: account(2, "0", "-1") },
+			Violation::HeldMismatch { account: account(2, "0", "-1"), disputed: d("0") },
+			Violation::TotalOverflow { account: account(3, "79228162514264337593543950335", "1") },
+			Violation::HeldMismatch { account: Account::new(4), disputed: d("3") },
+			Violation::DisputedOverflow { client: 5 },
+		]);
+
+		// without disputed sums, a single client goes through the history
+		assert_eq!(store.audit_client(4), [Violation::HeldMismatch { account: Account::new(4), disputed: d("3") }]);
+		assert_eq!(store.audit_client(5), [Violation::DisputedOverflow { client: 5 }]);
+		assert_eq!(store.audit_client(6), []);
+	}
+
+	#[test]
+	fn forgetful_backend() {
+		let mut store = Store::with_backend(CompactBackend::new().with_retention(2));
+		store.handle_deposit(1, 1, d("5")).unwrap();
+		store.handle_dispute(1, 1).unwrap();
+		store.handle_deposit(2, 1, d("1")).unwrap();
+		store.handle_deposit(3, 1, d("1")).unwrap();
+		assert_eq!(store.audit(), []);
+	}
+}
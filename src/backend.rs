@@ -0,0 +1,163 @@
+
+//! Where the `Store` keeps its accounts and transaction history.
+//!
+//! The store only needs to look up, insert and iterate both, which is what
+//! the repository traits cover. Anything implementing both of them is a
+//! `Backend` the store can run on.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+
+use crate::store::{Account, ClientId, TxId, TxRecord};
+
+/// Storage for client accounts
+pub trait AccountRepository {
+	fn get_account(&self, client: ClientId) -> Option<Account>;
+
+	/// Insert an account, replacing the one of the same client if any
+	fn insert_account(&mut self, account: Account) -> io::Result<()>;
+
+	/// All accounts, ordered by client id
+	fn accounts(&self) -> Box<dyn Iterator<Item = Account> + '_>;
+}
+
+/// Storage for the deposits and withdrawals that can still be disputed
+pub trait TxRepository {
+	fn get_tx(&self, txid: TxId) -> Option<TxRecord>;
+
+	/// Insert a tx, replacing the one with the same txid if any
+	fn insert_tx(&mut self, tx: TxRecord) -> io::Result<()>;
+
+	/// All txs, in no particular order
+	fn txs(&self) -> Box<dyn Iterator<Item = TxRecord> + '_>;
+
+	/// Whether a tx that can't be found was dropped from the history on
+	/// purpose, for backends that don't keep it all
+	fn is_expired(&self, _txid: TxId) -> bool {
+		false
+	}
+
+	/// Whether txs are ever dropped from the history, so that `txs` may
+	/// miss some
+	fn forgets_txs(&self) -> bool {
+		false
+	}
+}
+
+/// Everything a `Store` needs to keep its data
+pub trait Backend: AccountRepository + TxRepository {}
+
+impl<T: AccountRepository + TxRepository> Backend for T {}
+
+/// The default backend, which keeps everything in memory
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+	accounts: BTreeMap<ClientId, Account>,
+	history: HashMap<TxId, TxRecord>,
+}
+
+impl AccountRepository for MemoryBackend {
+	fn get_account(&self, client: ClientId) -> Option<Account> {
+		self.accounts.get(&client).copied()
+	}
+
+	fn insert_account(&mut self, account: Account) -> io::Result<()> {
+		self.accounts.insert(account.id, account);
+		Ok(())
+	}
+
+	fn accounts(&self) -> Box<dyn Iterator<Item = Account> + '_> {
+		Box::new(self.accounts.values().copied())
+	}
+}
+
+impl TxRepository for MemoryBackend {
+	fn get_tx(&self, txid: TxId) -> Option<TxRecord> {
+		self.history.get(&txid).copied()
+	}
+
+	fn insert_tx(&mut self, tx: TxRecord) -> io::Result<()> {
+		self.history.insert(tx.txid, tx);
+		Ok(())
+	}
+
+	fn txs(&self) -> Box<dyn Iterator<Item = TxRecord> + '_> {
+		Box::new(self.history.values().copied())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::store::{Store, Transaction};
+	use rust_decimal::Decimal;
+
+	/// Helper to create a decimal.
+	fn d(s: &str) -> Decimal {
+		s.parse().expect("invalid decimal")
+	}
+
+	/// A deliberately simple backend, to check the store only goes
+	/// through the traits
+	#[derive(Default)]
+	struct VecBackend {
+		accounts: Vec<Account>,
+		txs: Vec<TxRecord>,
+	}
+
+	impl AccountRepository for VecBackend {
+		fn get_account(&self, client: ClientId) -> Option<Account> {
+			self.accounts.iter().find(|a| a.id == client).copied()
+		}
+
+		fn insert_account(&mut self, account: Account) -> io::Result<()> {
+			self.accounts.retain(|a| a.id != account.id);
+			self.accounts.push(account);
+			self.accounts.sort_by_key(|a| a.id);
+			Ok(())
+		}
+
+		fn accounts(&self) -> Box<dyn Iterator<Item = Account> + '_> {
+			Box::new(self.accounts.iter().copied())
+		}
+	}
+
+	impl TxRepository for VecBackend {
+		fn get_tx(&self, txid: TxId) -> Option<TxRecord> {
+			self.txs.iter().find(|tx| tx.txid == txid).copied()
+		}
+
+		fn insert_tx(&mut self, tx: TxRecord) -> io::Result<()> {
+			self.txs.retain(|t| t.txid != tx.txid);
+			self.txs.push(tx);
+			Ok(())
+		}
+
+		fn txs(&self) -> Box<dyn Iterator<Item = TxRecord> + '_> {
+			Box::new(self.txs.iter().copied())
+		}
+	}
+
+	#[test]
+	fn pluggable_backend() {
+		let txs = [
+			Transaction::Deposit { txid: 1, client: 2, amount: d("10") },
+			Transaction::Deposit { txid: 2, client: 1, amount: d("5") },
+			Transaction::Withdrawal { txid: 3, client: 2, amount: d("3") },
+			Transaction::Withdrawal { txid: 4, client: 1, amount: d("6") },
+			Transaction::Dispute { client: 1, txid: 2 },
+			Transaction::Dispute { client: 2, txid: 1 },
+			Transaction::Resolve { client: 1, txid: 2 },
+			Transaction::Chargeback { client: 2, txid: 3 },
+		];
+
+		let mut memory = Store::new();
+		let mut vec = Store::with_backend(VecBackend::default());
+		for &tx in &txs {
+			assert_eq!(memory.apply(tx), vec.apply(tx), "{:?}", tx);
+		}
+		assert_eq!(memory.list_accounts().collect::<Vec<_>>(), vec.list_accounts().collect::<Vec<_>>());
+		assert_eq!(vec.backend().txs.len(), 3);
+		assert_eq!(vec.backend().get_tx(1), memory.get_transaction(1));
+	}
+}
@@ -0,0 +1,660 @@
+
+//! A memory-bounded backend for huge inputs.
+//!
+//! The history is kept in pages of consecutive txids, where every tx takes
+//! 15 bytes, instead of a hash map entry several times that size. This works
+//! well when txids are mostly increasing, as they are in practice; for
+//! sparse txids the `MemoryBackend` is the better choice.
+//!
+//! On top of that, pages can be spilled to a file when too many are in
+//! memory, coldest (oldest) first, and txs can be dropped altogether after a
+//! retention window, after which they can no longer be disputed.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use rust_decimal::Decimal;
+
+use crate::backend::{AccountRepository, TxRepository};
+use crate::store::{Account, ClientId, DisputeState, TxId, TxRecord, TxType, MAX_DECIMALS};
+
+const PAGE_BITS: u32 = 10;
+const PAGE_SIZE: usize = 1 << PAGE_BITS;
+
+/// seq: u32, client: u16, amount: i64, flags: u8
+const SLOT_BYTES: usize = 15;
+const PAGE_BYTES: usize = PAGE_SIZE * SLOT_BYTES;
+
+/// Amount stored for amounts that don't fit in an i64 of 10^-MAX_DECIMALS
+/// units, the real amount is in the overflow map
+const OVERFLOW: i64 = i64::MIN;
+
+type PageId = u32;
+
+fn page_of(txid: TxId) -> PageId {
+	txid >> PAGE_BITS
+}
+
+fn slot_of(txid: TxId) -> usize {
+	(txid as usize) & (PAGE_SIZE - 1)
+}
+
+/// A tx as stored in a page
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Slot {
+	/// The insertion sequence number, 0 for an empty slot
+	seq: u32,
+	client: ClientId,
+	amount: i64,
+	tp: TxType,
+	dispute_state: DisputeState,
+}
+
+impl Slot {
+	fn encode(&self, out: &mut [u8]) {
+		let tp = match self.tp {
+			TxType::Withdrawal => 1,
+			_ => 0,
+		};
+		let state = match self.dispute_state {
+			DisputeState::Normal => 0,
+			DisputeState::Disputed => 1,
+			DisputeState::Resolved => 2,
+			DisputeState::ChargedBack => 3,
+		};
+		out[0..4].copy_from_slice(&self.seq.to_le_bytes());
+		out[4..6].copy_from_slice(&self.client.to_le_bytes());
+		out[6..14].copy_from_slice(&self.amount.to_le_bytes());
+		out[14] = tp | state << 1;
+	}
+
+	/// Decode a slot, None if it's empty
+	fn decode(bytes: &[u8]) -> Option<Slot> {
+		let mut seq = [0; 4];
+		let mut client = [0; 2];
+		let mut amount = [0; 8];
+		seq.copy_from_slice(&bytes[0..4]);
+		client.copy_from_slice(&bytes[4..6]);
+		amount.copy_from_slice(&bytes[6..14]);
+
+		let seq = u32::from_le_bytes(seq);
+		if seq == 0 {
+			return None;
+		}
+		let flags = bytes[14];
+		Some(Slot {
+			seq,
+			client: u16::from_le_bytes(client),
+			amount: i64::from_le_bytes(amount),
+			tp: if flags & 1 == 0 { TxType::Deposit } else { TxType::Withdrawal },
+			dispute_state: match flags >> 1 & 3 {
+				0 => DisputeState::Normal,
+				1 => DisputeState::Disputed,
+				2 => DisputeState::Resolved,
+				_ => DisputeState::ChargedBack,
+			},
+		})
+	}
+}
+
+/// Convert an amount to units of 10^-MAX_DECIMALS, if it fits
+fn to_units(amount: Decimal) -> Option<i64> {
+	let mut scaled = amount;
+	scaled.rescale(MAX_DECIMALS);
+	if scaled.scale() != MAX_DECIMALS || scaled != amount {
+		return None;
+	}
+	let units = scaled.mantissa();
+	if units == OVERFLOW as i128 || units < i64::MIN as i128 || units > i64::MAX as i128 {
+		return None;
+	}
+	Some(units as i64)
+}
+
+#[derive(Debug)]
+struct Page {
+	bytes: Box<[u8]>,
+	/// The highest insertion seq on this page, to find the coldest pages
+	last_seq: u32,
+	/// Whether the page changed since it was last written to the spill file
+	dirty: bool,
+}
+
+impl Page {
+	fn new() -> Page {
+		Page {
+			bytes: vec![0; PAGE_BYTES].into_boxed_slice(),
+			last_seq: 0,
+			dirty: true,
+		}
+	}
+
+	fn slot(&self, idx: usize) -> Option<Slot> {
+		Slot::decode(&self.bytes[idx * SLOT_BYTES..(idx + 1) * SLOT_BYTES])
+	}
+
+	fn set_slot(&mut self, idx: usize, slot: &Slot) {
+		slot.encode(&mut self.bytes[idx * SLOT_BYTES..(idx + 1) * SLOT_BYTES]);
+		self.dirty = true;
+	}
+}
+
+/// A file cold pages are written to
+#[derive(Debug)]
+struct Spill {
+	file: File,
+	/// The maximum number of pages kept in memory
+	max_resident: usize,
+	/// Where each spilled page is written
+	offsets: HashMap<PageId, u64>,
+	/// Space of expired pages, reused before growing the file
+	free: Vec<u64>,
+	/// Free space at the end of the file
+	end: u64,
+}
+
+/// A backend that keeps the history in a compact form, see the module docs.
+///
+/// Failing to write the spill file is returned as an error from the insert,
+/// the page then stays in memory. So is inserting more than 2^32 txs, which
+/// would wrap the sequence numbers of the slots. Failing to read back what was written is
+/// fatal and panics.
+#[derive(Debug)]
+pub struct CompactBackend {
+	accounts: BTreeMap<ClientId, Account>,
+	/// The pages in memory
+	pages: HashMap<PageId, Page>,
+	/// All pages by their last_seq, oldest first
+	by_age: BTreeMap<u32, PageId>,
+	/// The pages in memory by their last_seq, oldest first
+	resident_by_age: BTreeMap<u32, PageId>,
+	/// Amounts that don't fit in a slot
+	overflow: HashMap<TxId, Decimal>,
+	/// Pages that were dropped because they fell out of the retention window
+	expired: HashSet<PageId>,
+	/// The last insertion sequence number
+	seq: u32,
+	/// The number of txs after which a tx is dropped
+	retention: Option<u32>,
+	spill: Option<Spill>,
+}
+
+impl Default for CompactBackend {
+	fn default() -> CompactBackend {
+		CompactBackend::new()
+	}
+}
+
+impl CompactBackend {
+	pub fn new() -> CompactBackend {
+		CompactBackend {
+			accounts: BTreeMap::new(),
+			pages: HashMap::new(),
+			by_age: BTreeMap::new(),
+			resident_by_age: BTreeMap::new(),
+			overflow: HashMap::new(),
+			expired: HashSet::new(),
+			seq: 0,
+			retention: None,
+			spill: None,
+		}
+	}
+
+	/// Drop txs once this many newer deposits and withdrawals came in.
+	/// Disputes on them fail with `Error::TxExpired`, and reuse of their
+	/// txids is no longer detected.
+	pub fn with_retention(mut self, txs: u32) -> CompactBackend {
+		self.retention = Some(txs);
+		self
+	}
+
+	/// Keep at most `max_resident_pages` pages of 1024 txids in memory, and
+	/// spill the others to a file at the given path, which is truncated
+	pub fn with_spill_file<P: AsRef<Path>>(mut self, path: P, max_resident_pages: usize) -> io::Result<CompactBackend> {
+		let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+		self.spill = Some(Spill {
+			file,
+			max_resident: max_resident_pages.max(1),
+			offsets: HashMap::new(),
+			free: Vec::new(),
+			end: 0,
+		});
+		Ok(self)
+	}
+
+	/// The number of pages in memory
+	pub fn resident_pages(&self) -> usize {
+		self.pages.len()
+	}
+
+	fn is_slot_expired(&self, slot: &Slot) -> bool {
+		self.retention.is_some_and(|r| self.seq - slot.seq >= r)
+	}
+
+	/// Read a page that isn't in memory from the spill file
+	fn read_spilled(&self, page: PageId) -> Option<Box<[u8]>> {
+		let spill = self.spill.as_ref()?;
+		let offset = *spill.offsets.get(&page)?;
+		let mut bytes = vec![0; PAGE_BYTES].into_boxed_slice();
+		let mut file = &spill.file;
+		file.seek(SeekFrom::Start(offset))
+			.and_then(|_| file.read_exact(&mut bytes))
+			.expect("failed to read spill file");
+		Some(bytes)
+	}
+
+	fn read_slot(&self, txid: TxId) -> Option<Slot> {
+		let page = page_of(txid);
+		let idx = slot_of(txid);
+		match self.pages.get(&page) {
+			Some(p) => p.slot(idx),
+			None => {
+				let bytes = self.read_spilled(page)?;
+				Slot::decode(&bytes[idx * SLOT_BYTES..(idx + 1) * SLOT_BYTES])
+			},
+		}
+	}
+
+	/// Get a page in memory, reading it back or creating it if needed
+	fn page_mut(&mut self, page: PageId) -> &mut Page {
+		if !self.pages.contains_key(&page) {
+			let loaded = self.read_spilled(page).map(|bytes| {
+				let mut p = Page {
+					bytes,
+					last_seq: 0,
+					dirty: false,
+				};
+				p.last_seq = (0..PAGE_SIZE).filter_map(|idx| p.slot(idx)).map(|s| s.seq).max().unwrap_or(0);
+				p
+			});
+			let p = loaded.unwrap_or_else(|| {
+				// the txs that expired with the page are forgotten for good,
+				// txids that were never used on it are just not found
+				self.expired.remove(&page);
+				Page::new()
+			});
+			self.pages.insert(page, p);
+		}
+		self.pages.get_mut(&page).expect("page was just inserted")
+	}
+
+	/// Spill the coldest pages until few enough are in memory
+	fn evict(&mut self) -> io::Result<()> {
+		let spill = match self.spill {
+			Some(ref mut spill) => spill,
+			None => return Ok(()),
+		};
+		while self.pages.len() > spill.max_resident {
+			// pages that only just came in have no age yet, keep those
+			let (&age, &page) = match self.resident_by_age.iter().next() {
+				Some(entry) => entry,
+				None => return Ok(()),
+			};
+			let p = &self.pages[&page];
+			if p.dirty || !spill.offsets.contains_key(&page) {
+				// the file is only ever read back by this process, so a page
+				// is rewritten in place
+				let (offset, reused) = match spill.offsets.get(&page) {
+					Some(&offset) => (offset, false),
+					None => match spill.free.pop() {
+						Some(offset) => (offset, true),
+						None => (spill.end, false),
+					},
+				};
+				let written = spill.file.seek(SeekFrom::Start(offset)).and_then(|_| spill.file.write_all(&p.bytes));
+				if let Err(err) = written {
+					if reused {
+						spill.free.push(offset);
+					}
+					return Err(err);
+				}
+				if offset == spill.end {
+					spill.end += PAGE_BYTES as u64;
+				}
+				spill.offsets.insert(page, offset);
+			}
+			self.resident_by_age.remove(&age);
+			self.pages.remove(&page);
+		}
+		Ok(())
+	}
+
+	/// Drop the pages that are entirely out of the retention window
+	fn expire(&mut self) {
+		let retention = match self.retention {
+			Some(r) => r,
+			None => return,
+		};
+		while let Some((&age, &page)) = self.by_age.iter().next() {
+			if self.seq - age < retention {
+				break;
+			}
+			self.by_age.remove(&age);
+			self.resident_by_age.remove(&age);
+			self.pages.remove(&page);
+			if let Some(ref mut spill) = self.spill {
+				if let Some(offset) = spill.offsets.remove(&page) {
+					spill.free.push(offset);
+				}
+			}
+			if !self.overflow.is_empty() {
+				self.overflow.retain(|&txid, _| page_of(txid) != page);
+			}
+			self.expired.insert(page);
+		}
+	}
+
+	/// Decode all txs of a page
+	fn page_txs(&self, page: PageId, bytes: &[u8]) -> Vec<TxRecord> {
+		(0..PAGE_SIZE).filter_map(|idx| {
+			let slot = Slot::decode(&bytes[idx * SLOT_BYTES..(idx + 1) * SLOT_BYTES])?;
+			let txid = page << PAGE_BITS | idx as u32;
+			self.to_record(txid, &slot)
+		}).collect()
+	}
+
+	fn to_record(&self, txid: TxId, slot: &Slot) -> Option<TxRecord> {
+		if self.is_slot_expired(slot) {
+			return None;
+		}
+		let amount = if slot.amount == OVERFLOW {
+			*self.overflow.get(&txid)?
+		} else {
+			Decimal::new(slot.amount, MAX_DECIMALS)
+		};
+		Some(TxRecord {
+			txid,
+			tp: slot.tp,
+			client: slot.client,
+			amount,
+			dispute_state: slot.dispute_state,
+		})
+	}
+}
+
+impl AccountRepository for CompactBackend {
+	fn get_account(&self, client: ClientId) -> Option<Account> {
+		self.accounts.get(&client).copied()
+	}
+
+	fn insert_account(&mut self, account: Account) -> io::Result<()> {
+		self.accounts.insert(account.id, account);
+		Ok(())
+	}
+
+	fn accounts(&self) -> Box<dyn Iterator<Item = Account> + '_> {
+		Box::new(self.accounts.values().copied())
+	}
+}
+
+impl TxRepository for CompactBackend {
+	fn get_tx(&self, txid: TxId) -> Option<TxRecord> {
+		let slot = self.read_slot(txid)?;
+		self.to_record(txid, &slot)
+	}
+
+	fn insert_tx(&mut self, tx: TxRecord) -> io::Result<()> {
+		let (page, idx) = (page_of(tx.txid), slot_of(tx.txid));
+
+		// an update of an existing tx keeps its place in the history
+		let existing = self.read_slot(tx.txid).filter(|s| !self.is_slot_expired(s));
+		let seq = match existing {
+			Some(slot) => slot.seq,
+			None => {
+				self.seq = self.seq.checked_add(1).ok_or_else(|| {
+					io::Error::other("the compact backend can't number more than 2^32 txs")
+				})?;
+				self.seq
+			},
+		};
+
+		let amount = match to_units(tx.amount) {
+			Some(units) => {
+				self.overflow.remove(&tx.txid);
+				units
+			},
+			None => {
+				self.overflow.insert(tx.txid, tx.amount);
+				OVERFLOW
+			},
+		};
+		let slot = Slot {
+			seq,
+			client: tx.client,
+			amount,
+			tp: tx.tp,
+			dispute_state: tx.dispute_state,
+		};
+
+		let p = self.page_mut(page);
+		p.set_slot(idx, &slot);
+		let old_age = p.last_seq;
+		p.last_seq = old_age.max(seq);
+		let age = p.last_seq;
+
+		if age != old_age {
+			self.by_age.remove(&old_age);
+			self.resident_by_age.remove(&old_age);
+			self.by_age.insert(age, page);
+		}
+		// also when it was just read back from the spill file
+		self.resident_by_age.insert(age, page);
+
+		self.expire();
+		self.evict()
+	}
+
+	fn txs(&self) -> Box<dyn Iterator<Item = TxRecord> + '_> {
+		let resident = self.pages.iter().flat_map(move |(&page, p)| self.page_txs(page, &p.bytes));
+		let spilled = self.spill.iter()
+			.flat_map(|spill| spill.offsets.keys())
+			.filter(move |page| !self.pages.contains_key(page))
+			.flat_map(move |&page| {
+				let bytes = self.read_spilled(page).expect("spilled page has no offset");
+				self.page_txs(page, &bytes)
+			});
+		Box::new(resident.chain(spilled))
+	}
+
+	fn is_expired(&self, txid: TxId) -> bool {
+		match self.read_slot(txid) {
+			Some(slot) => self.is_slot_expired(&slot),
+			None => self.expired.contains(&page_of(txid)),
+		}
+	}
+
+	fn forgets_txs(&self) -> bool {
+		self.retention.is_some()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::backend::MemoryBackend;
+	use crate::store::{Error, Store, Transaction};
+	use std::path::PathBuf;
+
+	/// Helper to create a decimal.
+	fn d(s: &str) -> Decimal {
+		s.parse().expect("invalid decimal")
+	}
+
+	fn temp_path(name: &str) -> PathBuf {
+		std::env::temp_dir().join(format!("kraken-compact-{}-{}", std::process::id(), name))
+	}
+
+	#[test]
+	fn slot_roundtrip() {
+		let slot = Slot {
+			seq: 123456,
+			client: 65535,
+			amount: -5,
+			tp: TxType::Withdrawal,
+			dispute_state: DisputeState::ChargedBack,
+		};
+		let mut bytes = [0; SLOT_BYTES];
+		slot.encode(&mut bytes);
+		assert_eq!(Slot::decode(&bytes), Some(slot));
+		assert_eq!(Slot::decode(&[0; SLOT_BYTES]), None);
+
+		assert_eq!(to_units(d("1.5")), Some(15000));
+		assert_eq!(to_units(d("0.0001")), Some(1));
+		assert_eq!(to_units(d("1.00001")), None);
+		assert_eq!(to_units(d("10000000000000000")), None);
+	}
+
+	/// Some txs over a few pages, with disputes on every kind of tx
+	fn txs() -> Vec<Transaction> {
+		let mut txs = Vec::new();
+		for txid in 0..5000u32 {
+			let client = (txid % 7) as u16;
+			let amount = Decimal::new(txid as i64 * 3 + 1, 3);
+			txs.push(Transaction::Deposit { txid: txid * 2, client, amount });
+			if txid % 3 == 0 {
+				txs.push(Transaction::Withdrawal { txid: txid * 2 + 1, client, amount: d("0.5") });
+			}
+			if txid > 100 && txid % 11 == 0 {
+				let disputed = txid * 2 - 200;
+				let client = ((txid - 100) % 7) as u16;
+				txs.push(Transaction::Dispute { client, txid: disputed });
+				match txid % 3 {
+					0 => txs.push(Transaction::Resolve { client, txid: disputed }),
+					1 => txs.push(Transaction::Chargeback { client, txid: disputed }),
+					_ => {},
+				}
+			}
+		}
+		txs.push(Transaction::Deposit { txid: 1_000_000, client: 1, amount: d("100000000000000000") });
+		txs.push(Transaction::Dispute { client: 1, txid: 1_000_000 });
+		txs
+	}
+
+	fn check_same_as_memory(mut compact: Store<CompactBackend>) -> Store<CompactBackend> {
+		let mut memory = Store::with_backend(MemoryBackend::default());
+		for tx in txs() {
+			assert_eq!(compact.apply(tx), memory.apply(tx), "{:?}", tx);
+		}
+		assert_eq!(compact.list_accounts().collect::<Vec<_>>(), memory.list_accounts().collect::<Vec<_>>());
+
+		let mut compact_txs = compact.transactions().collect::<Vec<_>>();
+		let mut memory_txs = memory.transactions().collect::<Vec<_>>();
+		compact_txs.sort_by_key(|tx| tx.txid);
+		memory_txs.sort_by_key(|tx| tx.txid);
+		assert_eq!(compact_txs, memory_txs);
+		compact
+	}
+
+	#[test]
+	fn same_as_memory() {
+		check_same_as_memory(Store::with_backend(CompactBackend::new()));
+	}
+
+	#[test]
+	fn spill() {
+		let path = temp_path("spill");
+		let backend = CompactBackend::new().with_spill_file(&path, 2).unwrap();
+		let store = check_same_as_memory(Store::with_backend(backend));
+		assert!(store.backend().resident_pages() <= 2);
+
+		// pages are rewritten in place, so there's one copy of each of the 11
+		let len = std::fs::metadata(&path).unwrap().len();
+		assert!(len <= 11 * PAGE_BYTES as u64, "{}", len);
+		std::fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn spill_reuses_expired_pages() {
+		let path = temp_path("spill-reuse");
+		let backend = CompactBackend::new().with_retention(2000).with_spill_file(&path, 1).unwrap();
+		let mut store = Store::with_backend(backend);
+		for txid in 0..50000 {
+			store.handle_deposit(txid, 1, d("1")).unwrap();
+		}
+		let len = std::fs::metadata(&path).unwrap().len();
+		assert!(len <= 4 * PAGE_BYTES as u64, "{}", len);
+		store.handle_dispute(1, 48500).unwrap();
+		std::fs::remove_file(&path).unwrap();
+	}
+
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn spill_write_error() {
+		let backend = CompactBackend::new().with_spill_file("/dev/full", 1).unwrap();
+		let mut store = Store::with_backend(backend);
+		store.handle_deposit(0, 1, d("1")).unwrap();
+		match store.handle_deposit(5000, 1, d("1")) {
+			Err(Error::Storage { .. }) => {},
+			other => panic!("expected a storage error, got {:?}", other),
+		}
+		// the page that couldn't be written is still there
+		assert_eq!(store.backend().resident_pages(), 2);
+		assert_eq!(store.transactions().count(), 2);
+	}
+
+	#[test]
+	fn retention() {
+		let mut store = Store::with_backend(CompactBackend::new().with_retention(3000));
+		for txid in 0..5000 {
+			store.handle_deposit(txid, 1, d("1")).unwrap();
+		}
+		assert_eq!(store.handle_dispute(1, 1999), Err(Error::TxExpired { txid: 1999 }));
+		store.handle_dispute(1, 2000).unwrap();
+		assert_eq!(store.handle_dispute(1, 100), Err(Error::TxExpired { txid: 100 }));
+		assert_eq!(store.handle_dispute(1, 6000), Err(Error::TxNotFound { txid: 6000 }));
+		assert_eq!(store.transactions().count(), 3000);
+
+		// whole pages out of the window are gone
+		assert!(store.backend().resident_pages() <= 4);
+
+		// updating a tx doesn't make it any younger
+		store.handle_resolve(1, 2000).unwrap();
+		store.handle_deposit(5000, 1, d("1")).unwrap();
+		assert_eq!(store.handle_dispute(1, 2000), Err(Error::TxExpired { txid: 2000 }));
+	}
+
+	#[test]
+	fn seq_exhausted() {
+		let mut backend = CompactBackend::new();
+		backend.seq = u32::MAX - 1;
+		let mut store = Store::with_backend(backend);
+		store.handle_deposit(1, 1, d("1")).unwrap();
+		let err = store.handle_deposit(2, 1, d("1")).unwrap_err();
+		assert_eq!(err.code(), "storage_failed");
+		assert_eq!(store.get_transaction(2), None);
+		// updates don't take a new number
+		store.handle_dispute(1, 1).unwrap();
+	}
+
+	#[test]
+	fn retention_repopulated_page() {
+		let mut store = Store::with_backend(CompactBackend::new().with_retention(1000));
+		for txid in (0..10).chain(1024..3024) {
+			store.handle_deposit(txid, 1, d("1")).unwrap();
+		}
+		assert_eq!(store.handle_dispute(1, 5), Err(Error::TxExpired { txid: 5 }));
+		assert_eq!(store.handle_dispute(1, 30), Err(Error::TxExpired { txid: 30 }));
+
+		store.handle_deposit(20, 1, d("1")).unwrap();
+		assert_eq!(store.handle_dispute(1, 30), Err(Error::TxNotFound { txid: 30 }));
+		store.handle_dispute(1, 20).unwrap();
+	}
+
+	#[test]
+	fn retention_with_spill() {
+		let path = temp_path("retention-spill");
+		let backend = CompactBackend::new().with_retention(5000).with_spill_file(&path, 1).unwrap();
+		let mut store = Store::with_backend(backend);
+		for txid in 0..10000 {
+			store.handle_deposit(txid, (txid % 3) as u16, d("0.0001")).unwrap();
+		}
+		assert_eq!(store.handle_dispute(1, 4999), Err(Error::TxExpired { txid: 4999 }));
+		store.handle_dispute(2, 5000).unwrap();
+		store.handle_chargeback(2, 5000).unwrap();
+		assert_eq!(store.transactions().count(), 5000);
+		assert_eq!(store.backend().resident_pages(), 1);
+		std::fs::remove_file(&path).unwrap();
+	}
+}
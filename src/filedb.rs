@@ -0,0 +1,392 @@
+
+//! A backend that keeps the accounts and history in files, so they are
+//! still there after a run.
+//!
+//! Both are tables of fixed size records in a format of our own, which are
+//! rewritten in place when they change; they are not a database file that
+//! SQL tools can open. Reopening a directory rebuilds the index of where
+//! every record is. `FileBackend::write_sql` exports the tables as plain SQL
+//! statements, for whatever database the results are to be queried in:
+//!
+//! ```sh
+//! kraken txs.csv --tables results
+//! kraken export-sql results > results.sql
+//! ```
+//!
+//! Nothing is synced to disk, this is for looking at the results of a run
+//! and not for recovering from a crash; `PersistentStore` does that.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use rust_decimal::Decimal;
+
+use crate::backend::{AccountRepository, TxRepository};
+use crate::store::{Account, ClientId, DisputeState, TxId, TxRecord, TxType};
+
+const ACCOUNTS_FILE: &str = "accounts";
+const TXS_FILE: &str = "txs";
+
+/// client: u16, available: Decimal, held: Decimal, locked: u8
+const ACCOUNT_BYTES: usize = 35;
+
+/// txid: u32, client: u16, type: u8, dispute state: u8, amount: Decimal
+const TX_BYTES: usize = 24;
+
+fn invalid(msg: String) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn decimal(bytes: &[u8]) -> Decimal {
+	let mut buf = [0; 16];
+	buf.copy_from_slice(bytes);
+	Decimal::deserialize(buf)
+}
+
+fn encode_account(account: &Account) -> [u8; ACCOUNT_BYTES] {
+	let mut out = [0; ACCOUNT_BYTES];
+	out[0..2].copy_from_slice(&account.id.to_le_bytes());
+	out[2..18].copy_from_slice(&account.available.serialize());
+	out[18..34].copy_from_slice(&account.held.serialize());
+	out[34] = account.locked as u8;
+	out
+}
+
+fn decode_account(bytes: &[u8]) -> Option<Account> {
+	Some(Account {
+		id: u16::from_le_bytes([bytes[0], bytes[1]]),
+		available: decimal(&bytes[2..18]),
+		held: decimal(&bytes[18..34]),
+		locked: match bytes[34] {
+			0 => false,
+			1 => true,
+			_ => return None,
+		},
+	})
+}
+
+fn encode_tx(tx: &TxRecord) -> [u8; TX_BYTES] {
+	let mut out = [0; TX_BYTES];
+	out[0..4].copy_from_slice(&tx.txid.to_le_bytes());
+	out[4..6].copy_from_slice(&tx.client.to_le_bytes());
+	out[6] = match tx.tp {
+		TxType::Deposit => 0,
+		TxType::Withdrawal => 1,
+		TxType::Dispute => 2,
+		TxType::Resolve => 3,
+		TxType::Chargeback => 4,
+	};
+	out[7] = match tx.dispute_state {
+		DisputeState::Normal => 0,
+		DisputeState::Disputed => 1,
+		DisputeState::Resolved => 2,
+		DisputeState::ChargedBack => 3,
+	};
+	out[8..24].copy_from_slice(&tx.amount.serialize());
+	out
+}
+
+fn decode_tx(bytes: &[u8]) -> Option<TxRecord> {
+	Some(TxRecord {
+		txid: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+		client: u16::from_le_bytes([bytes[4], bytes[5]]),
+		tp: match bytes[6] {
+			0 => TxType::Deposit,
+			1 => TxType::Withdrawal,
+			// only deposits and withdrawals are stored
+			_ => return None,
+		},
+		dispute_state: match bytes[7] {
+			0 => DisputeState::Normal,
+			1 => DisputeState::Disputed,
+			2 => DisputeState::Resolved,
+			3 => DisputeState::ChargedBack,
+			_ => return None,
+		},
+		amount: decimal(&bytes[8..24]),
+	})
+}
+
+/// A file of fixed size records
+#[derive(Debug)]
+struct Table {
+	file: File,
+	record_bytes: usize,
+	/// Free space at the end of the file
+	end: u64,
+}
+
+impl Table {
+	/// Open a table, creating it if needed, and call `found` with every
+	/// record and its offset
+	fn open(path: &Path, record_bytes: usize, truncate: bool, mut found: impl FnMut(u64, &[u8]) -> io::Result<()>) -> io::Result<Table> {
+		let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(truncate).open(path)?;
+		let mut bytes = Vec::new();
+		file.read_to_end(&mut bytes)?;
+		for (idx, record) in bytes.chunks_exact(record_bytes).enumerate() {
+			found((idx * record_bytes) as u64, record)?;
+		}
+
+		// drop a partially written record at the end
+		let end = (bytes.len() - bytes.len() % record_bytes) as u64;
+		file.set_len(end)?;
+		Ok(Table { file, record_bytes, end })
+	}
+
+	fn read(&self, offset: u64) -> Vec<u8> {
+		let mut bytes = vec![0; self.record_bytes];
+		let mut file = &self.file;
+		file.seek(SeekFrom::Start(offset))
+			.and_then(|_| file.read_exact(&mut bytes))
+			.expect("failed to read a table");
+		bytes
+	}
+
+	/// Write a record at `offset`, or at the end for None, and return where
+	fn write(&mut self, offset: Option<u64>, bytes: &[u8]) -> io::Result<u64> {
+		let offset = offset.unwrap_or(self.end);
+		self.file.seek(SeekFrom::Start(offset))?;
+		self.file.write_all(bytes)?;
+		if offset == self.end {
+			self.end += self.record_bytes as u64;
+		}
+		Ok(offset)
+	}
+}
+
+/// A backend that keeps everything in files in a directory, see the module
+/// docs.
+///
+/// The accounts are also kept in memory, there are at most 65536 of them.
+/// The history is read back from its file when needed, only where each tx
+/// is stays in memory. Failing to read back what was written is fatal and
+/// panics.
+#[derive(Debug)]
+pub struct FileBackend {
+	accounts: BTreeMap<ClientId, (u64, Account)>,
+	accounts_table: Table,
+	txs: HashMap<TxId, u64>,
+	txs_table: Table,
+}
+
+impl FileBackend {
+	/// Open the tables in `dir`, creating it if needed, with the accounts
+	/// and txs that were saved in it
+	pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<FileBackend> {
+		FileBackend::open_dir(dir.as_ref(), false)
+	}
+
+	/// Open the tables in `dir`, creating it if needed, and drop anything
+	/// that was saved in it
+	pub fn create<P: AsRef<Path>>(dir: P) -> io::Result<FileBackend> {
+		FileBackend::open_dir(dir.as_ref(), true)
+	}
+
+	fn open_dir(dir: &Path, truncate: bool) -> io::Result<FileBackend> {
+		fs::create_dir_all(dir)?;
+
+		let mut accounts = BTreeMap::new();
+		let accounts_table = Table::open(&dir.join(ACCOUNTS_FILE), ACCOUNT_BYTES, truncate, |offset, bytes| {
+			let account = decode_account(bytes).ok_or_else(|| invalid(format!("invalid account at {}", offset)))?;
+			accounts.insert(account.id, (offset, account));
+			Ok(())
+		})?;
+
+		let mut txs = HashMap::new();
+		let txs_table = Table::open(&dir.join(TXS_FILE), TX_BYTES, truncate, |offset, bytes| {
+			let tx = decode_tx(bytes).ok_or_else(|| invalid(format!("invalid tx at {}", offset)))?;
+			txs.insert(tx.txid, offset);
+			Ok(())
+		})?;
+
+		Ok(FileBackend {
+			accounts,
+			accounts_table,
+			txs,
+			txs_table,
+		})
+	}
+
+	/// Write the accounts and txs as SQL statements that create and fill
+	/// an `accounts` and a `txs` table. Amounts are exact, as text.
+	pub fn write_sql<W: Write>(&self, mut out: W) -> io::Result<()> {
+		writeln!(out, "BEGIN TRANSACTION;")?;
+		writeln!(out, "CREATE TABLE accounts (client INTEGER PRIMARY KEY, available TEXT NOT NULL, held TEXT NOT NULL, total TEXT NOT NULL, locked INTEGER NOT NULL);")?;
+		for account in self.accounts() {
+			writeln!(
+				out,
+				"INSERT INTO accounts VALUES ({}, '{}', '{}', '{}', {});",
+				account.id,
+				account.available,
+				account.held,
+				account.available + account.held,
+				account.locked as u8,
+			)?;
+		}
+		writeln!(out, "CREATE TABLE txs (tx INTEGER PRIMARY KEY, type TEXT NOT NULL, client INTEGER NOT NULL, amount TEXT NOT NULL, state TEXT NOT NULL);")?;
+		let mut txs = self.txs().collect::<Vec<_>>();
+		txs.sort_by_key(|tx| tx.txid);
+		for tx in txs {
+			writeln!(
+				out,
+				"INSERT INTO txs VALUES ({}, '{}', {}, '{}', '{}');",
+				tx.txid,
+				tx.tp.name(),
+				tx.client,
+				tx.amount,
+				tx.dispute_state.name(),
+			)?;
+		}
+		writeln!(out, "COMMIT;")
+	}
+}
+
+impl AccountRepository for FileBackend {
+	fn get_account(&self, client: ClientId) -> Option<Account> {
+		self.accounts.get(&client).map(|&(_, account)| account)
+	}
+
+	fn insert_account(&mut self, account: Account) -> io::Result<()> {
+		let offset = self.accounts.get(&account.id).map(|&(offset, _)| offset);
+		let offset = self.accounts_table.write(offset, &encode_account(&account))?;
+		self.accounts.insert(account.id, (offset, account));
+		Ok(())
+	}
+
+	fn accounts(&self) -> Box<dyn Iterator<Item = Account> + '_> {
+		Box::new(self.accounts.values().map(|&(_, account)| account))
+	}
+}
+
+impl TxRepository for FileBackend {
+	fn get_tx(&self, txid: TxId) -> Option<TxRecord> {
+		let offset = *self.txs.get(&txid)?;
+		decode_tx(&self.txs_table.read(offset))
+	}
+
+	fn insert_tx(&mut self, tx: TxRecord) -> io::Result<()> {
+		let offset = self.txs.get(&tx.txid).copied();
+		let offset = self.txs_table.write(offset, &encode_tx(&tx))?;
+		self.txs.insert(tx.txid, offset);
+		Ok(())
+	}
+
+	fn txs(&self) -> Box<dyn Iterator<Item = TxRecord> + '_> {
+		Box::new(self.txs.values().filter_map(move |&offset| decode_tx(&self.txs_table.read(offset))))
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::backend::MemoryBackend;
+	use crate::store::{Store, Transaction};
+	use std::path::PathBuf;
+
+	/// Helper to create a decimal.
+	fn d(s: &str) -> Decimal {
+		s.parse().expect("invalid decimal")
+	}
+
+	fn temp_path(name: &str) -> PathBuf {
+		std::env::temp_dir().join(format!("kraken-filedb-{}-{}", std::process::id(), name))
+	}
+
+	fn txs() -> Vec<Transaction> {
+		vec![
+			Transaction::Deposit { txid: 1, client: 1, amount: d("1.5") },
+			Transaction::Deposit { txid: 2, client: 2, amount: d("100000000000000000") },
+			Transaction::Withdrawal { txid: 3, client: 1, amount: d("0.25") },
+			Transaction::Dispute { client: 1, txid: 3 },
+			Transaction::Dispute { client: 2, txid: 2 },
+			Transaction::Chargeback { client: 2, txid: 2 },
+			Transaction::Deposit { txid: 4, client: 1, amount: d("2") },
+			Transaction::Dispute { client: 1, txid: 4 },
+			Transaction::Resolve { client: 1, txid: 4 },
+			Transaction::Deposit { txid: 5, client: 1, amount: d("1") },
+		]
+	}
+
+	fn sorted_txs<B: TxRepository>(backend: &B) -> Vec<TxRecord> {
+		let mut txs = backend.txs().collect::<Vec<_>>();
+		txs.sort_by_key(|tx| tx.txid);
+		txs
+	}
+
+	#[test]
+	fn same_as_memory_and_reopens() {
+		let dir = temp_path("reopen");
+		let mut file = Store::with_backend(FileBackend::create(&dir).unwrap());
+		let mut memory = Store::with_backend(MemoryBackend::default());
+		for tx in txs() {
+			assert_eq!(file.apply(tx), memory.apply(tx), "{:?}", tx);
+		}
+		assert_eq!(sorted_txs(file.backend()), sorted_txs(memory.backend()));
+
+		// every change was written in place
+		let len = fs::metadata(dir.join(TXS_FILE)).unwrap().len();
+		assert_eq!(len, 5 * TX_BYTES as u64);
+
+		let reopened = FileBackend::open(&dir).unwrap();
+		assert_eq!(reopened.accounts().collect::<Vec<_>>(), memory.backend().accounts().collect::<Vec<_>>());
+		assert_eq!(sorted_txs(&reopened), sorted_txs(memory.backend()));
+
+		assert_eq!(FileBackend::create(&dir).unwrap().accounts().count(), 0);
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn partial_record() {
+		let dir = temp_path("partial");
+		let mut backend = FileBackend::create(&dir).unwrap();
+		let tx = TxRecord {
+			txid: 7,
+			tp: TxType::Withdrawal,
+			client: 3,
+			amount: d("-0.0001"),
+			dispute_state: DisputeState::Disputed,
+		};
+		backend.insert_tx(tx).unwrap();
+		drop(backend);
+
+		let mut file = OpenOptions::new().append(true).open(dir.join(TXS_FILE)).unwrap();
+		file.write_all(&[1, 2, 3]).unwrap();
+		drop(file);
+
+		let mut backend = FileBackend::open(&dir).unwrap();
+		assert_eq!(backend.get_tx(7), Some(tx));
+		backend.insert_tx(TxRecord { txid: 8, ..tx }).unwrap();
+		assert_eq!(sorted_txs(&FileBackend::open(&dir).unwrap()), [tx, TxRecord { txid: 8, ..tx }]);
+
+		fs::write(dir.join(TXS_FILE), [0xff; TX_BYTES]).unwrap();
+		assert_eq!(FileBackend::open(&dir).unwrap_err().kind(), io::ErrorKind::InvalidData);
+		// only deposits and withdrawals are stored
+		let mut dispute = encode_tx(&tx);
+		dispute[6] = 2;
+		fs::write(dir.join(TXS_FILE), dispute).unwrap();
+		assert_eq!(FileBackend::open(&dir).unwrap_err().kind(), io::ErrorKind::InvalidData);
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn sql() {
+		let dir = temp_path("sql");
+		let mut store = Store::with_backend(FileBackend::create(&dir).unwrap());
+		store.handle_deposit(2, 1, d("1.5")).unwrap();
+		store.handle_dispute(1, 2).unwrap();
+
+		let mut out = Vec::new();
+		store.backend().write_sql(&mut out).unwrap();
+		assert_eq!(String::from_utf8(out).unwrap(), "\
+BEGIN TRANSACTION;
+CREATE TABLE accounts (client INTEGER PRIMARY KEY, available TEXT NOT NULL, held TEXT NOT NULL, total TEXT NOT NULL, locked INTEGER NOT NULL);
+INSERT INTO accounts VALUES (1, '0.0', '1.5', '1.5', 0);
+CREATE TABLE txs (tx INTEGER PRIMARY KEY, type TEXT NOT NULL, client INTEGER NOT NULL, amount TEXT NOT NULL, state TEXT NOT NULL);
+INSERT INTO txs VALUES (2, 'deposit', 1, '1.5', 'disputed');
+COMMIT;
+");
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -0,0 +1,304 @@
+//! The `http` mode: a JSON API over a shared store.
+//!
+//! - `POST /transactions` applies a tx, given as a JSON object with the
+//!   fields of an input row. Answers 201 when it was applied and 200 when
+//!   it was an idempotent replay.
+//! - `GET /accounts` lists all accounts
+//! - `GET /accounts/{client}` gets one account
+//! - `GET /transactions/{txid}` gets a deposit or withdrawal
+//!
+//! Errors come back as `{"error": <reason>, "message": <details>}` with a
+//! 4xx status, or 500 when the store failed to save, the reasons being the
+//! same as in the rejects file. Errors about a tx also have its `client`
+//! and `txid`, and the fields of the error, like `available` and `required`
+//! for `insufficient_funds`. Only as
+//! much HTTP/1.1 as that needs is supported: bodies need a length, and
+//! connections are kept open unless the client asks to close.
+
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+
+use kraken::{ClientId, Error, Outcome, Store, Transaction, TxId};
+
+use crate::{json, json_row, parse, serve, tx_json, OutputLine, Reject, COLUMNS};
+
+/// The largest request body that is accepted
+const MAX_BODY: usize = 64 * 1024;
+
+struct Request {
+	method: String,
+	path: String,
+	body: String,
+	/// Whether the connection is closed after the response
+	close: bool,
+}
+
+struct Response {
+	status: u16,
+	body: String,
+}
+
+impl Response {
+	fn ok(status: u16, body: String) -> Response {
+		Response { status, body }
+	}
+
+	fn error(status: u16, reason: &str, message: &str) -> Response {
+		Response::error_with(status, reason, &[], message)
+	}
+
+	/// An error with more members between the reason and the message,
+	/// their values already in JSON
+	fn error_with(status: u16, reason: &str, members: &[(&str, String)], message: &str) -> Response {
+		let mut body = format!(r#"{{"error":{}"#, json::string(reason));
+		for (key, value) in members {
+			body += &format!(",{}:{}", json::string(key), value);
+		}
+		body += &format!(r#","message":{}}}"#, json::string(message));
+		Response { status, body }
+	}
+
+	/// A tx that wasn't applied, with the tx if it could be parsed
+	fn reject(reject: Reject, tx: Option<&Transaction>) -> Response {
+		let (message, fields) = match reject {
+			Reject::Malformed => ("the body is not a valid transaction object".to_string(), Vec::new()),
+			Reject::UnknownType => ("unknown transaction type".to_string(), Vec::new()),
+			Reject::MissingAmount => ("deposits and withdrawals need an amount".to_string(), Vec::new()),
+			Reject::Store(ref err) => (err.to_string(), error_fields(err)),
+		};
+
+		// the client and txid of the error itself win over the tx's, they
+		// differ when a dispute is applied to the owner of the tx
+		let from_tx = tx.map(|tx| vec![("client", tx.client().to_string()), ("txid", tx.txid().to_string())]);
+		let mut members = Vec::new();
+		for key in ["client", "txid"] {
+			let value = fields.iter().chain(from_tx.iter().flatten()).find(|(k, _)| *k == key);
+			members.extend(value.cloned());
+		}
+		members.extend(fields.iter().filter(|(key, _)| !["client", "txid"].contains(key)).cloned());
+		Response::error_with(status_of(&reject), reject.code(), &members, &message)
+	}
+}
+
+/// The fields of a store error, with their values in JSON
+fn error_fields(err: &Error) -> Vec<(&'static str, String)> {
+	let name = |s: &str| json::string(s);
+	match *err {
+		Error::InsufficientFunds { available, required } => {
+			vec![("available", available.to_string()), ("required", required.to_string())]
+		},
+		Error::CreditLimitExceeded { available, limit, required } => vec![
+			("available", available.to_string()),
+			("limit", limit.to_string()),
+			("required", required.to_string()),
+		],
+		Error::TxNotFound { txid } | Error::TxExpired { txid } | Error::DuplicateTx { txid } => {
+			vec![("txid", txid.to_string())]
+		},
+		Error::TxInWrongState { txid, action, state } => vec![
+			("txid", txid.to_string()),
+			("action", name(action.name())),
+			("state", name(state.name())),
+		],
+		Error::ClientMismatch { txid, expected, got } => vec![
+			("txid", txid.to_string()),
+			("expected", expected.to_string()),
+			("got", got.to_string()),
+		],
+		Error::NonPositiveAmount { amount } | Error::ExcessivePrecision { amount } => {
+			vec![("amount", amount.to_string())]
+		},
+		Error::HeldUnderflow { held, required } => vec![("held", held.to_string()), ("required", required.to_string())],
+		Error::Overflow { client, available, held } => vec![
+			("client", client.to_string()),
+			("available", available.to_string()),
+			("held", held.to_string()),
+		],
+		Error::LedgerOverflow { account, amount } => {
+			vec![("account", name(&account.to_string())), ("amount", amount.to_string())]
+		},
+		Error::NotDisputable { txid, tp } => vec![("txid", txid.to_string()), ("type", name(tp.name()))],
+		Error::AccountLocked { client, action } => vec![("client", client.to_string()), ("action", name(action.name()))],
+		Error::Storage { .. } => Vec::new(),
+	}
+}
+
+/// The status code for a tx that wasn't applied
+fn status_of(reject: &Reject) -> u16 {
+	match reject {
+		Reject::Malformed | Reject::UnknownType | Reject::MissingAmount => 400,
+		Reject::Store(err) => match err {
+			Error::TxNotFound { .. } => 404,
+			Error::TxExpired { .. } => 410,
+			Error::ClientMismatch { .. } => 403,
+			Error::AccountLocked { .. } => 423,
+			Error::DuplicateTx { .. } | Error::TxInWrongState { .. } => 409,
+			Error::InsufficientFunds { .. }
+			| Error::CreditLimitExceeded { .. }
+			| Error::HeldUnderflow { .. }
+			| Error::Overflow { .. }
+			| Error::LedgerOverflow { .. }
+			| Error::NotDisputable { .. }
+			| Error::NonPositiveAmount { .. }
+			| Error::ExcessivePrecision { .. } => 422,
+			Error::Storage { .. } => 500,
+		},
+	}
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+	match status {
+		200 => "OK",
+		201 => "Created",
+		400 => "Bad Request",
+		403 => "Forbidden",
+		404 => "Not Found",
+		405 => "Method Not Allowed",
+		409 => "Conflict",
+		410 => "Gone",
+		411 => "Length Required",
+		413 => "Payload Too Large",
+		422 => "Unprocessable Entity",
+		423 => "Locked",
+		500 => "Internal Server Error",
+		_ => "",
+	}
+}
+
+/// Accept connections on `addr` until the process is stopped
+pub fn serve(addr: &str, store: Store) -> io::Result<()> {
+	let store = Arc::new(Mutex::new(store));
+	let listener = TcpListener::bind(addr)?;
+	eprintln!("listening on {}", listener.local_addr()?);
+	for stream in listener.incoming() {
+		serve::spawn(stream, &store, handle);
+	}
+	Ok(())
+}
+
+fn handle(stream: &TcpStream, store: &Mutex<Store>) -> io::Result<()> {
+	let mut reader = BufReader::new(stream);
+	let mut writer = BufWriter::new(stream);
+	loop {
+		let (response, close) = match read_request(&mut reader)? {
+			None => return Ok(()),
+			Some(Ok(request)) => (route(&request, store), request.close),
+			// the rest of the stream can't be trusted after a bad request
+			Some(Err(response)) => (response, true),
+		};
+		write!(writer, "HTTP/1.1 {} {}\r\n", response.status, reason_phrase(response.status))?;
+		write!(writer, "Content-Type: application/json\r\nContent-Length: {}\r\n", response.body.len() + 1)?;
+		if close {
+			write!(writer, "Connection: close\r\n")?;
+		}
+		write!(writer, "\r\n{}\n", response.body)?;
+		writer.flush()?;
+		if close {
+			return Ok(());
+		}
+	}
+}
+
+/// Read the next request, or `None` at the end of the stream
+fn read_request(reader: &mut impl BufRead) -> io::Result<Option<Result<Request, Response>>> {
+	let mut line = String::new();
+	if reader.read_line(&mut line)? == 0 {
+		return Ok(None);
+	}
+	let bad_request = || Ok(Some(Err(Response::error(400, "bad_request", "malformed HTTP request"))));
+	let mut parts = line.split_whitespace();
+	let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+		(Some(method), Some(target), Some(version)) => (method, target, version),
+		_ => return bad_request(),
+	};
+	let mut request = Request {
+		method: method.to_string(),
+		// the query string isn't used by any endpoint
+		path: target.split('?').next().unwrap_or("").to_string(),
+		body: String::new(),
+		close: version == "HTTP/1.0",
+	};
+
+	let mut length = None;
+	loop {
+		let mut header = String::new();
+		if reader.read_line(&mut header)? == 0 {
+			return bad_request();
+		}
+		let header = header.trim_end();
+		if header.is_empty() {
+			break;
+		}
+		let (name, value) = match header.split_once(':') {
+			Some((name, value)) => (name.trim(), value.trim()),
+			None => return bad_request(),
+		};
+		if name.eq_ignore_ascii_case("content-length") {
+			match value.parse::<usize>() {
+				Ok(n) => length = Some(n),
+				Err(_) => return bad_request(),
+			}
+		} else if name.eq_ignore_ascii_case("transfer-encoding") {
+			return Ok(Some(Err(Response::error(411, "length_required", "only bodies with a Content-Length are supported"))));
+		} else if name.eq_ignore_ascii_case("connection") {
+			request.close = value.eq_ignore_ascii_case("close");
+		}
+	}
+
+	let length = length.unwrap_or(0);
+	if length > MAX_BODY {
+		return Ok(Some(Err(Response::error(413, "body_too_large", "the request body is too large"))));
+	}
+	let mut body = vec![0; length];
+	reader.read_exact(&mut body)?;
+	request.body = match String::from_utf8(body) {
+		Ok(body) => body,
+		Err(_) => return bad_request(),
+	};
+	Ok(Some(Ok(request)))
+}
+
+fn route(request: &Request, store: &Mutex<Store>) -> Response {
+	let segments = request.path.trim_matches('/').split('/').collect::<Vec<_>>();
+	match (request.method.as_str(), segments.as_slice()) {
+		("POST", ["transactions"]) => post_transaction(&request.body, store),
+		("GET", ["accounts"]) => {
+			let store = serve::lock(store);
+			let accounts = store.list_accounts().map(|a| OutputLine::from(a).to_json()).collect::<Vec<_>>();
+			Response::ok(200, format!("[{}]", accounts.join(",")))
+		},
+		("GET", ["accounts", client]) => match client.parse::<ClientId>() {
+			Ok(client) => match serve::lock(store).list_accounts().find(|a| a.client == client) {
+				Some(account) => Response::ok(200, OutputLine::from(account).to_json()),
+				None => Response::error(404, "account_not_found", &format!("no account for client {}", client)),
+			},
+			Err(_) => Response::error(400, "bad_request", "the client id must be a 16 bit number"),
+		},
+		("GET", ["transactions", txid]) => match txid.parse::<TxId>() {
+			Ok(txid) => match serve::lock(store).get_transaction(txid) {
+				Some(tx) => Response::ok(200, tx_json(&tx)),
+				None => Response::reject(Reject::Store(Error::TxNotFound { txid }), None),
+			},
+			Err(_) => Response::error(400, "bad_request", "the tx id must be a 32 bit number"),
+		},
+		(_, ["transactions"]) | (_, ["accounts"]) | (_, ["accounts", _]) | (_, ["transactions", _]) => {
+			Response::error(405, "method_not_allowed", &format!("{} is not supported here", request.method))
+		},
+		_ => Response::error(404, "not_found", "no such endpoint"),
+	}
+}
+
+fn post_transaction(body: &str, store: &Mutex<Store>) -> Response {
+	let headers = csv::StringRecord::from(&COLUMNS[..]);
+	let tx = match json_row(body.trim()).ok_or(Reject::Malformed).and_then(|row| parse(&row, &headers)) {
+		Ok(tx) => tx,
+		Err(reject) => return Response::reject(reject, None),
+	};
+	let (status, result) = match serve::lock(store).apply(tx) {
+		Ok(Outcome::Applied) => (201, "applied"),
+		Ok(Outcome::Replayed) => (200, "replayed"),
+		Err(err) => return Response::reject(Reject::Store(err), Some(&tx)),
+	};
+	Response::ok(status, format!(r#"{{"tx":{},"result":"{}"}}"#, tx.txid(), result))
+}
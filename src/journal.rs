@@ -0,0 +1,234 @@
+//! A double-entry journal behind the account balances.
+//!
+//! Every accepted operation posts entries that move an amount from one
+//! ledger account to another, so the balances of all ledger accounts
+//! always sum to zero. Money comes in from and goes out to
+//! `external:bank`, and chargebacks send it to `external:chargebacks`:
+//!
+//! - a deposit moves its amount from the bank to the client's available
+//!   funds, a withdrawal moves it back
+//! - a dispute moves the disputed amount from available to held, a resolve
+//!   moves it back, and a chargeback sends it to the chargebacks account
+//! - a provisional credit for a disputed withdrawal is a claim on the
+//!   chargebacks account, held until it is resolved, which gives it back,
+//!   or charged back, which makes it available
+//!
+//! The trial balance checks that the journal sums to zero and agrees with
+//! the balances of every account.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use rust_decimal::Decimal;
+
+use crate::backend::Backend;
+use crate::store::{ClientId, Error, Store};
+
+/// An account of the journal
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LedgerAccount {
+	/// A client's available funds
+	Available(ClientId),
+	/// A client's held funds
+	Held(ClientId),
+	/// Where deposits come from and withdrawals go to
+	Bank,
+	/// Where charged back funds go to
+	Chargebacks,
+}
+
+impl fmt::Display for LedgerAccount {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			LedgerAccount::Available(client) => write!(f, "client:{}:available", client),
+			LedgerAccount::Held(client) => write!(f, "client:{}:held", client),
+			LedgerAccount::Bank => f.write_str("external:bank"),
+			LedgerAccount::Chargebacks => f.write_str("external:chargebacks"),
+		}
+	}
+}
+
+/// A movement of an amount from one ledger account to another
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry {
+	pub from: LedgerAccount,
+	pub to: LedgerAccount,
+	pub amount: Decimal,
+}
+
+/// The balances of all ledger accounts that had an entry
+#[derive(Debug, Default)]
+pub struct Journal {
+	balances: BTreeMap<LedgerAccount, Decimal>,
+	entries: u64,
+}
+
+impl Journal {
+	/// Post an entry, which keeps the journal balanced. Fails without
+	/// posting anything if a balance would overflow.
+	pub fn post(&mut self, entry: Entry) -> Result<(), Error> {
+		let overflow = |account| Error::LedgerOverflow { account, amount: entry.amount };
+		let from = self.balance(entry.from).checked_sub(entry.amount).ok_or_else(|| overflow(entry.from))?;
+		let to = self.balance(entry.to).checked_add(entry.amount).ok_or_else(|| overflow(entry.to))?;
+		self.balances.insert(entry.from, from);
+		self.balances.insert(entry.to, to);
+		self.entries += 1;
+		Ok(())
+	}
+
+	/// The balance of a ledger account, zero if it had no entries
+	pub fn balance(&self, account: LedgerAccount) -> Decimal {
+		self.balances.get(&account).copied().unwrap_or_default()
+	}
+
+	/// All ledger accounts with their balances, the clients' first
+	pub fn balances(&self) -> impl Iterator<Item = (LedgerAccount, Decimal)> + '_ {
+		self.balances.iter().map(|(&account, &balance)| (account, balance))
+	}
+
+	/// How many entries were posted
+	pub fn entries(&self) -> u64 {
+		self.entries
+	}
+}
+
+/// A ledger account whose balance doesn't match the client's account
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mismatch {
+	pub account: LedgerAccount,
+	pub journal: Decimal,
+	pub store: Decimal,
+}
+
+/// The result of a trial balance
+#[derive(Debug, PartialEq, Eq)]
+pub struct TrialBalance {
+	/// The sum of all ledger accounts
+	pub total: Decimal,
+	pub mismatches: Vec<Mismatch>,
+}
+
+impl TrialBalance {
+	pub fn is_balanced(&self) -> bool {
+		self.total.is_zero() && self.mismatches.is_empty()
+	}
+}
+
+impl<B: Backend> Store<B> {
+	/// Check the journal against the accounts. `None` unless the store was
+	/// created `with_journal`.
+	pub fn trial_balance(&self) -> Option<TrialBalance> {
+		let journal = self.journal()?;
+		let mut mismatches = Vec::new();
+		let mut check = |account, store| {
+			let journal = journal.balance(account);
+			if journal != store {
+				mismatches.push(Mismatch { account, journal, store });
+			}
+		};
+		for account in self.backend().accounts() {
+			check(LedgerAccount::Available(account.id), account.available);
+			check(LedgerAccount::Held(account.id), account.held);
+		}
+		// client ledger accounts of clients the store doesn't know
+		for (account, _) in journal.balances() {
+			if let LedgerAccount::Available(client) | LedgerAccount::Held(client) = account {
+				if self.backend().get_account(client).is_none() {
+					check(account, Decimal::ZERO);
+				}
+			}
+		}
+
+		Some(TrialBalance {
+			total: journal.balances().map(|(_, balance)| balance).sum(),
+			mismatches,
+		})
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::store::{DisputePolicy, Transaction};
+
+	fn d(s: &str) -> Decimal {
+		s.parse().expect("invalid decimal")
+	}
+
+	fn apply_all(store: &mut Store, txs: &[Transaction]) {
+		for &tx in txs {
+			let _ = store.apply(tx);
+			assert!(store.trial_balance().unwrap().is_balanced(), "unbalanced after {:?}", tx);
+		}
+	}
+
+	#[test]
+	fn postings() {
+		let mut store = Store::new().with_journal();
+		apply_all(&mut store, &[
+			Transaction::Deposit { txid: 1, client: 1, amount: d("10") },
+			Transaction::Withdrawal { txid: 2, client: 1, amount: d("3") },
+			// refused, posts nothing
+			Transaction::Withdrawal { txid: 3, client: 1, amount: d("30") },
+			Transaction::Deposit { txid: 4, client: 2, amount: d("5") },
+			Transaction::Dispute { client: 1, txid: 2 },
+			Transaction::Resolve { client: 1, txid: 2 },
+			Transaction::Dispute { client: 2, txid: 4 },
+			Transaction::Chargeback { client: 2, txid: 4 },
+		]);
+		let journal = store.journal().unwrap();
+		assert_eq!(journal.entries(), 7);
+		assert_eq!(journal.balances().collect::<Vec<_>>(), [
+			(LedgerAccount::Available(1), d("7")),
+			(LedgerAccount::Available(2), d("0")),
+			(LedgerAccount::Held(1), d("0")),
+			(LedgerAccount::Held(2), d("0")),
+			(LedgerAccount::Bank, d("-12")),
+			(LedgerAccount::Chargebacks, d("5")),
+		]);
+	}
+
+	#[test]
+	fn provisional_credit() {
+		let mut store = Store::new().with_journal().with_dispute_policy(DisputePolicy::ByTxType);
+		apply_all(&mut store, &[
+			Transaction::Deposit { txid: 1, client: 1, amount: d("10") },
+			Transaction::Withdrawal { txid: 2, client: 1, amount: d("4") },
+			Transaction::Withdrawal { txid: 3, client: 1, amount: d("1") },
+			Transaction::Dispute { client: 1, txid: 2 },
+			Transaction::Dispute { client: 1, txid: 3 },
+			Transaction::Resolve { client: 1, txid: 3 },
+			Transaction::Chargeback { client: 1, txid: 2 },
+		]);
+		let journal = store.journal().unwrap();
+		assert_eq!(journal.balance(LedgerAccount::Available(1)), d("9"));
+		assert_eq!(journal.balance(LedgerAccount::Chargebacks), d("-4"));
+	}
+
+	#[test]
+	fn overflow() {
+		let mut store = Store::new().with_journal();
+		store.apply(Transaction::Deposit { txid: 1, client: 1, amount: Decimal::MAX }).unwrap();
+		// neither account overflows, but the bank does
+		let err = store.apply(Transaction::Deposit { txid: 2, client: 2, amount: d("1") }).unwrap_err();
+		assert_eq!(err, Error::LedgerOverflow { account: LedgerAccount::Bank, amount: d("1") });
+		assert!(store.list_accounts().find(|a| a.client == 2).unwrap().total.is_zero());
+		assert_eq!(store.journal().unwrap().entries(), 1);
+		assert!(store.trial_balance().unwrap().is_balanced());
+	}
+
+	#[test]
+	fn mismatch() {
+		let mut store = Store::new().with_journal();
+		apply_all(&mut store, &[Transaction::Deposit { txid: 1, client: 1, amount: d("10") }]);
+		store.post(LedgerAccount::Held(2), LedgerAccount::Available(1), d("1")).unwrap();
+		let balance = store.trial_balance().unwrap();
+		assert!(!balance.is_balanced());
+		assert_eq!(balance.total, d("0"));
+		assert_eq!(balance.mismatches, [
+			Mismatch { account: LedgerAccount::Available(1), journal: d("11"), store: d("10") },
+			Mismatch { account: LedgerAccount::Held(2), journal: d("-1"), store: d("0") },
+		]);
+		assert_eq!(Store::new().trial_balance(), None);
+	}
+}
@@ -0,0 +1,165 @@
+//! Just enough JSON for flat objects, one per line. Values are kept as
+//! their text, so that numbers go through the same parsing as CSV fields.
+
+use std::fmt::Write;
+
+/// Parse an object with string, number, boolean or null values into its
+/// fields. Null values become empty strings.
+pub fn parse_object(text: &str) -> Option<Vec<(String, String)>> {
+	let mut parser = Parser { text, pos: 0 };
+	let mut fields = Vec::new();
+
+	parser.expect('{')?;
+	if !parser.eat('}') {
+		loop {
+			let key = parser.string()?;
+			parser.expect(':')?;
+			fields.push((key, parser.value()?));
+			if parser.eat('}') {
+				break;
+			}
+			parser.expect(',')?;
+		}
+	}
+	parser.skip_whitespace();
+	if parser.pos == text.len() { Some(fields) } else { None }
+}
+
+/// Quote a string for JSON output
+pub fn string(s: &str) -> String {
+	let mut out = String::with_capacity(s.len() + 2);
+	out.push('"');
+	for c in s.chars() {
+		match c {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			'\n' => out.push_str("\\n"),
+			'\r' => out.push_str("\\r"),
+			'\t' => out.push_str("\\t"),
+			c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+			c => out.push(c),
+		}
+	}
+	out.push('"');
+	out
+}
+
+struct Parser<'a> {
+	text: &'a str,
+	pos: usize,
+}
+
+impl<'a> Parser<'a> {
+	fn rest(&self) -> &'a str {
+		&self.text[self.pos..]
+	}
+
+	fn skip_whitespace(&mut self) {
+		let rest = self.rest();
+		self.pos += rest.len() - rest.trim_start().len();
+	}
+
+	/// Skip past `c` if it comes next
+	fn eat(&mut self, c: char) -> bool {
+		self.skip_whitespace();
+		if self.rest().starts_with(c) {
+			self.pos += c.len_utf8();
+			true
+		} else {
+			false
+		}
+	}
+
+	fn expect(&mut self, c: char) -> Option<()> {
+		if self.eat(c) { Some(()) } else { None }
+	}
+
+	fn value(&mut self) -> Option<String> {
+		self.skip_whitespace();
+		if self.rest().starts_with('"') {
+			return self.string();
+		}
+		for &(word, value) in &[("null", ""), ("true", "true"), ("false", "false")] {
+			if self.rest().starts_with(word) {
+				self.pos += word.len();
+				return Some(value.to_string());
+			}
+		}
+		self.number()
+	}
+
+	fn number(&mut self) -> Option<String> {
+		let rest = self.rest();
+		let len = rest
+			.find(|c: char| !(c.is_ascii_digit() || "+-.eE".contains(c)))
+			.unwrap_or(rest.len());
+		if len == 0 {
+			return None;
+		}
+		self.pos += len;
+		Some(rest[..len].to_string())
+	}
+
+	fn string(&mut self) -> Option<String> {
+		self.expect('"')?;
+		let mut out = String::new();
+		let mut chars = self.rest().char_indices();
+		while let Some((i, c)) = chars.next() {
+			match c {
+				'"' => {
+					self.pos += i + 1;
+					return Some(out);
+				},
+				'\\' => out.push(match chars.next()?.1 {
+					'"' => '"',
+					'\\' => '\\',
+					'/' => '/',
+					'b' => '\u{8}',
+					'f' => '\u{c}',
+					'n' => '\n',
+					'r' => '\r',
+					't' => '\t',
+					'u' => {
+						let hex = (0..4).map(|_| chars.next().map(|(_, c)| c)).collect::<Option<String>>()?;
+						char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?
+					},
+					_ => return None,
+				}),
+				c => out.push(c),
+			}
+		}
+		None
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn fields(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+		pairs.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect()
+	}
+
+	#[test]
+	fn parse() {
+		assert_eq!(
+			parse_object(r#" { "type": "deposit", "client": 1, "tx" :2, "amount": 1.5e0 } "#),
+			Some(fields(&[("type", "deposit"), ("client", "1"), ("tx", "2"), ("amount", "1.5e0")])),
+		);
+		assert_eq!(
+			parse_object(r#"{"type":"dis\"pute","amount":null,"x":true}"#),
+			Some(fields(&[("type", "dis\"pute"), ("amount", ""), ("x", "true")])),
+		);
+		assert_eq!(parse_object("{}"), Some(Vec::new()));
+		assert_eq!(parse_object(r#"{"type":"deposit""#), None);
+		assert_eq!(parse_object(r#"{"type":"deposit"} x"#), None);
+		assert_eq!(parse_object(r#"{"type":[1]}"#), None);
+		assert_eq!(parse_object("deposit,1,2,3"), None);
+	}
+
+	#[test]
+	fn quote() {
+		assert_eq!(string("a\"b\\c\nd\u{1}"), r#""a\"b\\c\nd\u0001""#);
+		assert_eq!(parse_object(&format!("{{\"k\":{}}}", string("x\"\\\n"))), Some(fields(&[("k", "x\"\\\n")])));
+	}
+}
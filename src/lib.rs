@@ -0,0 +1,39 @@
+
+//! A simple payments engine: it keeps client accounts and applies
+//! deposits, withdrawals and disputes on them.
+//!
+//! Everything goes through `Store::apply`:
+//!
+//! ```
+//! use kraken::{Outcome, Store, Transaction};
+//! use rust_decimal::Decimal;
+//!
+//! let mut store = Store::new();
+//! let tx = Transaction::Deposit { txid: 1, client: 1, amount: Decimal::new(15, 1) };
+//! assert_eq!(store.apply(tx), Ok(Outcome::Applied));
+//! ```
+
+mod audit;
+mod backend;
+mod compact;
+mod filedb;
+mod journal;
+mod persist;
+mod shard;
+mod statement;
+mod store;
+
+pub use audit::Violation;
+pub use backend::{AccountRepository, Backend, MemoryBackend, TxRepository};
+pub use compact::CompactBackend;
+pub use filedb::FileBackend;
+pub use journal::{Entry, Journal, LedgerAccount, Mismatch, TrialBalance};
+pub use persist::{FileStorage, NoStorage, PersistentStore, Storage};
+pub use shard::{ShardedStore, Shards, TxResult};
+pub use statement::StatementLine;
+
+pub use store::{
+	validate_amount, Account, AccountOrder, AccountSummary, ClientId, DisputePolicy, DisputeState,
+	DuplicatePolicy, Error, Event, LockedPolicy, NegativeBalancePolicy, Outcome, OwnershipPolicy,
+	Precision, Rounding, Store, Transaction, TxId, TxRecord, TxType, MAX_DECIMALS,
+};
@@ -1,20 +1,52 @@
 
-mod store;
-
-use std::{env, fs, io};
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+use std::{fmt, fs, io, panic, process};
 
 use rust_decimal::Decimal;
 use serde::{Serialize, Deserialize};
 
-use store::{AccountSummary, Store};
+mod args;
+mod http;
+mod json;
+mod serve;
+
+use kraken::{
+	validate_amount, AccountSummary, Backend, ClientId, CompactBackend, FileBackend, FileStorage, Outcome,
+	PersistentStore, ShardedStore, Shards, StatementLine, Store, Transaction, TrialBalance, TxId, TxRecord, TxType,
+	Violation,
+};
+
+use args::{parse_args, Args, InputFormat, Mode, OutputFormat};
 
 #[derive(Debug, Deserialize)]
 struct InputTx<'a> {
-	#[serde(borrow)]
+	#[serde(rename = "type", borrow)]
 	tx_type: &'a str,
 	client: u16,
+	#[serde(rename = "tx")]
 	txid: u32,
-	amount: Decimal,
+	/// Only present for deposits and withdrawals. Kept as text, since serde
+	/// would go through f64 and round it.
+	amount: Option<&'a str>,
+}
+
+/// Parse a tx type name, accepting a few common aliases
+fn parse_tx_type(name: &str) -> Option<TxType> {
+	let is = |alias: &str| name.eq_ignore_ascii_case(alias);
+	if is("deposit") {
+		Some(TxType::Deposit)
+	} else if is("withdrawal") || is("withdraw") {
+		Some(TxType::Withdrawal)
+	} else if is("dispute") {
+		Some(TxType::Dispute)
+	} else if is("resolve") {
+		Some(TxType::Resolve)
+	} else if is("chargeback") || is("charge_back") {
+		Some(TxType::Chargeback)
+	} else {
+		None
+	}
 }
 
 #[derive(Debug, Serialize)]
@@ -38,48 +70,886 @@ impl From<AccountSummary> for OutputLine {
 	}
 }
 
+impl OutputLine {
+	fn to_json(&self) -> String {
+		format!(
+			r#"{{"client":{},"available":{},"held":{},"total":{},"locked":{}}}"#,
+			self.client, self.available, self.held, self.total, self.locked,
+		)
+	}
+}
 
-fn main() {
-	let mut store = Store::new();
+fn tx_json(tx: &TxRecord) -> String {
+	format!(
+		r#"{{"tx":{},"type":"{}","client":{},"amount":{},"state":"{}"}}"#,
+		tx.txid, tx.tp.name(), tx.client, tx.amount, tx.dispute_state.name(),
+	)
+}
 
-	let input_file = env::args().nth(1).expect("no input file provided");
-	let input = fs::File::open(input_file).expect("failed to open input file");
+/// Rows read from an input, in either format
+enum Input<R: io::Read> {
+	Csv(csv::Reader<R>),
+	Jsonl { lines: io::Lines<io::BufReader<R>>, line: u64 },
+}
 
-	let mut reader = csv::ReaderBuilder::new()
-		.buffer_capacity(1024^2)
-		.delimiter(b',')
-		.has_headers(true)
-		.from_reader(io::BufReader::new(input));
+impl<R: io::Read> Input<R> {
+	fn new(format: InputFormat, reader: R) -> Input<R> {
+		match format {
+			InputFormat::Csv => Input::Csv(
+				csv::ReaderBuilder::new()
+					.buffer_capacity(1024 * 1024)
+					.delimiter(b',')
+					.has_headers(true)
+					.trim(csv::Trim::All)
+					.flexible(true)
+					.from_reader(reader),
+			),
+			InputFormat::Jsonl => Input::Jsonl {
+				lines: io::BufReader::with_capacity(1024 * 1024, reader).lines(),
+				line: 0,
+			},
+		}
+	}
 
-	let stdout = io::stdout();
-	let mut writer = csv::WriterBuilder::new()
-		.buffer_capacity(1024^2)
-		.delimiter(b',')
-		.has_headers(true)
-		.from_writer(stdout.lock());
-
-	let mut record = csv::StringRecord::new();
-	while reader.read_record(&mut record).expect("error reading CSV file") {
-		let tx = record.deserialize::<InputTx>(None).expect("wrong format");
-
-		let ret = match tx.tx_type {
-			"deposit" => store.handle_deposit(tx.txid, tx.client, tx.amount),
-			"withdraw" => store.handle_withdrawal(tx.txid, tx.client, tx.amount),
-			"dispute" => store.handle_dispute(tx.client, tx.txid),
-			"resolve" => store.handle_resolve(tx.client, tx.txid),
-			"chargeback" => store.handle_chargeback(tx.client, tx.txid),
-			_ => continue, // ignoring, should probably log error
+	/// The names of the columns of the rows
+	fn headers(&mut self) -> csv::StringRecord {
+		match self {
+			Input::Csv(reader) => reader.headers().unwrap_or_else(|err| read_failed(err)).clone(),
+			Input::Jsonl { .. } => csv::StringRecord::from(&COLUMNS[..]),
+		}
+	}
+
+	/// Read the next row, returning its line number
+	fn read(&mut self, record: &mut csv::StringRecord) -> Option<u64> {
+		match self {
+			Input::Csv(reader) => match reader.read_record(record).unwrap_or_else(|err| read_failed(err)) {
+				true => Some(record.position().map_or(0, |p| p.line())),
+				false => None,
+			},
+			Input::Jsonl { lines, line } => loop {
+				let text = lines.next()?.unwrap_or_else(|err| read_failed(csv::Error::from(err)));
+				*line += 1;
+				if text.trim().is_empty() {
+					continue;
+				}
+				// a row without fields is rejected as malformed
+				*record = json_row(text.trim()).unwrap_or_default();
+				return Some(*line);
+			},
+		}
+	}
+}
+
+/// The output of a decompressor process, which fails if the process does
+struct Decompressed {
+	program: &'static str,
+	child: process::Child,
+	stdout: process::ChildStdout,
+	done: bool,
+}
+
+impl Decompressed {
+	fn spawn(path: &str, program: &'static str) -> io::Result<Box<dyn io::Read>> {
+		let mut child = process::Command::new(program)
+			.arg("-dc")
+			.stdin(fs::File::open(path)?)
+			.stdout(process::Stdio::piped())
+			.spawn()
+			.map_err(|err| io::Error::new(err.kind(), format!("failed to run {}: {}", program, err)))?;
+		let stdout = child.stdout.take().unwrap();
+		Ok(Box::new(Decompressed { program, child, stdout, done: false }))
+	}
+}
+
+impl io::Read for Decompressed {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let n = self.stdout.read(buf)?;
+		if n == 0 && !buf.is_empty() && !self.done {
+			self.done = true;
+			let status = self.child.wait()?;
+			if !status.success() {
+				let msg = format!("{} failed with {}", self.program, status);
+				return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+			}
+		}
+		Ok(n)
+	}
+}
+
+/// Open an input, `-` being stdin. Compressed files are decompressed by
+/// the `gzip` or `zstd` program, going by their extension; those are only
+/// needed at runtime, and only for such files, see the `--help`.
+fn open_input(path: &str) -> io::Result<Box<dyn io::Read>> {
+	if path == "-" {
+		return Ok(Box::new(io::stdin()));
+	}
+	match path.rsplit('.').next() {
+		Some("gz") => Decompressed::spawn(path, "gzip"),
+		Some("zst") => Decompressed::spawn(path, "zstd"),
+		_ => Ok(Box::new(fs::File::open(path)?)),
+	}
+}
+
+/// An input with the row that's next from it
+struct Source {
+	input: Input<Box<dyn io::Read>>,
+	headers: csv::StringRecord,
+	/// Where the original fields are, the columns may be in any order
+	columns: [Option<usize>; 4],
+	record: csv::StringRecord,
+	/// The line of `record`, or `None` at the end
+	line: Option<u64>,
+	/// The last txid read, used for merging
+	txid: TxId,
+}
+
+impl Source {
+	fn open(path: &str, format: InputFormat) -> Source {
+		let reader = open_input(path).unwrap_or_else(|err| {
+			fail(EXIT_IO, format!("failed to open {}: {}", path, err))
+		});
+		let mut input = Input::new(format, reader);
+		let headers = input.headers();
+		let column = |name: &str| headers.iter().position(|h| h == name);
+		let columns = [column("type"), column("client"), column("tx"), column("amount")];
+		let mut source = Source { input, headers, columns, record: csv::StringRecord::new(), line: None, txid: 0 };
+		source.advance();
+		source
+	}
+
+	fn advance(&mut self) {
+		self.line = self.input.read(&mut self.record);
+		// only deposits and withdrawals have a txid of their own, the other
+		// rows reference one and stay right after the row before them
+		let tp = parse_tx_type(self.field(0).trim());
+		if self.line.is_some() && matches!(tp, Some(TxType::Deposit) | Some(TxType::Withdrawal)) {
+			if let Ok(txid) = self.field(2).trim().parse() {
+				self.txid = txid;
+			}
+		}
+	}
+
+	fn field(&self, i: usize) -> &str {
+		self.columns[i].and_then(|c| self.record.get(c)).unwrap_or("")
+	}
+
+	/// The current row, for the rejects file
+	fn row(&self, file: usize) -> Row {
+		Row {
+			file,
+			line: self.line.unwrap_or(0),
+			tx_type: self.field(0).to_string(),
+			client: self.field(1).to_string(),
+			tx: self.field(2).to_string(),
+			amount: self.field(3).to_string(),
+		}
+	}
+}
+
+/// The rows of all inputs, either one input after the other or merged by
+/// txid. Merging is stable, so each input should be ordered by txid and
+/// rows with the same txid stay in input order.
+struct Rows {
+	sources: Vec<Source>,
+	merge: bool,
+	/// The source of the row that was returned last
+	last: Option<usize>,
+}
+
+impl Rows {
+	/// The index of the source with the next row
+	fn next(&mut self) -> Option<usize> {
+		if let Some(last) = self.last {
+			self.sources[last].advance();
+		}
+		let mut remaining = self.sources.iter().enumerate().filter(|(_, s)| s.line.is_some());
+		self.last = if self.merge {
+			remaining.min_by_key(|&(i, s)| (s.txid, i)).map(|(i, _)| i)
+		} else {
+			remaining.next().map(|(i, _)| i)
 		};
+		self.last
+	}
+}
 
-		if let Err(_err) = ret {
-			// handle error on ret, but spec says we should ignore errors, can't log either
-			// perhaps log to stderr would be ok here
+/// Why a row was not applied
+#[derive(Debug)]
+enum Reject {
+	/// The row couldn't be parsed
+	Malformed,
+	/// The tx type isn't known
+	UnknownType,
+	/// A deposit or withdrawal without amount
+	MissingAmount,
+	/// The store refused the tx
+	Store(kraken::Error),
+}
+
+impl Reject {
+	fn code(&self) -> &'static str {
+		match self {
+			Reject::Malformed => "malformed_row",
+			Reject::UnknownType => "unknown_type",
+			Reject::MissingAmount => "missing_amount",
+			Reject::Store(err) => err.code(),
+		}
+	}
+}
+
+impl InputTx<'_> {
+	fn to_transaction(&self) -> Result<Transaction, Reject> {
+		let (txid, client) = (self.txid, self.client);
+		let tp = parse_tx_type(self.tx_type).ok_or(Reject::UnknownType)?;
+		let amount = self.amount.map(parse_amount).transpose()?;
+		Ok(match (tp, amount) {
+			(TxType::Deposit, Some(amount)) => Transaction::Deposit { txid, client, amount },
+			(TxType::Withdrawal, Some(amount)) => Transaction::Withdrawal { txid, client, amount },
+			(TxType::Deposit, None) | (TxType::Withdrawal, None) => return Err(Reject::MissingAmount),
+			(TxType::Dispute, _) => Transaction::Dispute { client, txid },
+			(TxType::Resolve, _) => Transaction::Resolve { client, txid },
+			(TxType::Chargeback, _) => Transaction::Chargeback { client, txid },
+		})
+	}
+}
+
+/// Parse an amount exactly, in decimal or scientific notation. Decimal
+/// rounds away digits past its 28 or so, which can only be decimal places,
+/// so an amount that doesn't fit is refused as too precise.
+fn parse_amount(text: &str) -> Result<Decimal, Reject> {
+	let scientific = text.contains(['e', 'E']);
+	let parsed = if scientific { Decimal::from_scientific(text) } else { text.parse() };
+	let amount = parsed.map_err(|_| Reject::Malformed)?;
+	// significant digits of the text against those that were kept
+	let mantissa = text.split(['e', 'E']).next().unwrap_or("");
+	let digits = mantissa.chars().filter(char::is_ascii_digit).collect::<String>();
+	let written = digits.trim_start_matches('0').trim_end_matches('0').len();
+	let kept = amount.normalize().mantissa().unsigned_abs();
+	let kept = if kept == 0 { 0 } else { kept.to_string().trim_end_matches('0').len() };
+	if kept < written {
+		return Err(Reject::Store(kraken::Error::ExcessivePrecision { amount }));
+	}
+	Ok(amount)
+}
+
+/// The columns of a row, in the order of the spec
+const COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+/// Turn a JSON object into a row, with its fields in the order of `COLUMNS`
+fn json_row(text: &str) -> Option<csv::StringRecord> {
+	let fields = json::parse_object(text)?;
+	Some(COLUMNS.iter().map(|&c| fields.iter().find(|(k, _)| k == c).map_or("", |(_, v)| v.as_str())).collect())
+}
+
+/// Parse a row into a tx
+fn parse(record: &csv::StringRecord, headers: &csv::StringRecord) -> Result<Transaction, Reject> {
+	let tx = record.deserialize::<InputTx>(Some(headers)).map_err(|_| Reject::Malformed)?;
+	tx.to_transaction()
+}
+
+/// Called with the sequence number and the result of every submitted tx
+type Done<'a> = dyn FnMut(u64, Result<Outcome, kraken::Error>) + 'a;
+
+/// Applies txs, either right away or on worker threads
+trait Engine {
+	/// What's left when all txs are done
+	type State;
+
+	/// Apply a tx. Its result is passed to `done`, on this or a later call.
+	fn submit(&mut self, seq: u64, tx: Transaction, done: &mut Done);
+
+	/// Wait for all txs to be done
+	fn finish(self, done: &mut Done) -> Self::State;
+
+	/// The trial balance of the journal after the txs submitted so far, for
+	/// engines that apply them right away and keep a journal
+	fn trial_balance(&self) -> Option<TrialBalance> {
+		None
+	}
+
+	/// The violated invariants after the txs submitted so far, for engines
+	/// that apply them right away
+	fn audit(&self) -> Option<Vec<Violation>> {
+		None
+	}
+
+	/// The violated invariants of the accounts a tx may have changed, for
+	/// engines that apply txs right away
+	fn audit_tx(&self, _tx: &Transaction) -> Option<Vec<Violation>> {
+		None
+	}
+}
+
+impl<B: Backend> Engine for Store<B> {
+	type State = Store<B>;
+
+	fn submit(&mut self, seq: u64, tx: Transaction, done: &mut Done) {
+		done(seq, self.apply(tx));
+	}
+
+	fn finish(self, _done: &mut Done) -> Store<B> {
+		self
+	}
+
+	fn trial_balance(&self) -> Option<TrialBalance> {
+		Store::trial_balance(self)
+	}
+
+	fn audit(&self) -> Option<Vec<Violation>> {
+		Some(Store::audit(self))
+	}
+
+	fn audit_tx(&self, tx: &Transaction) -> Option<Vec<Violation>> {
+		Some(Store::audit_tx(self, tx))
+	}
+}
+
+impl<B: Backend + Send + 'static> Engine for ShardedStore<B> {
+	type State = Shards<B>;
+
+	fn submit(&mut self, seq: u64, tx: Transaction, done: &mut Done) {
+		self.apply(seq, tx);
+		for (seq, result) in self.results() {
+			done(seq, result);
+		}
+	}
+
+	fn finish(self, done: &mut Done) -> Shards<B> {
+		let (results, shards) = ShardedStore::finish(self);
+		for (seq, result) in results {
+			done(seq, result);
 		}
+		shards
+	}
+}
+
+impl Engine for PersistentStore<FileStorage> {
+	type State = PersistentStore<FileStorage>;
+
+	fn submit(&mut self, seq: u64, tx: Transaction, done: &mut Done) {
+		match self.apply(tx) {
+			Ok(result) => done(seq, result),
+			Err(err) => fail(EXIT_IO, format!("error saving the state: {}", err)),
+		}
+	}
+
+	fn finish(self, _done: &mut Done) -> PersistentStore<FileStorage> {
+		self
+	}
+
+	fn trial_balance(&self) -> Option<TrialBalance> {
+		self.store().trial_balance()
 	}
 
-	// Print all account summaries
-	for account in store.list_accounts() {
-		let output: OutputLine = account.into();
-		writer.serialize(output).expect("writing to stdout failed");
+	fn audit(&self) -> Option<Vec<Violation>> {
+		Some(self.store().audit())
+	}
+
+	fn audit_tx(&self, tx: &Transaction) -> Option<Vec<Violation>> {
+		Some(self.store().audit_tx(tx))
+	}
+}
+
+/// The original fields of a row, and where it came from
+struct Row {
+	file: usize,
+	line: u64,
+	tx_type: String,
+	client: String,
+	tx: String,
+	amount: String,
+}
+
+/// The rejects file, written in input order even when results come back
+/// out of order
+struct Rejects {
+	path: String,
+	writer: csv::Writer<fs::File>,
+	/// The names of the inputs, when there's more than one
+	files: Option<Vec<String>>,
+	counts: BTreeMap<&'static str, u64>,
+	/// Rows that were submitted and have no result yet, by submission order
+	pending: BTreeMap<u64, Row>,
+	/// Rejected rows that wait for earlier rows to be done
+	ready: BTreeMap<u64, (Row, &'static str)>,
+}
+
+impl Rejects {
+	fn create(path: &str, inputs: &[String]) -> Rejects {
+		let mut writer = csv::Writer::from_path(path).unwrap_or_else(|err| write_failed(path, err));
+		let files = if inputs.len() > 1 { Some(inputs.to_vec()) } else { None };
+		let header = ["file", "line", "type", "client", "tx", "amount", "reason"];
+		let header = if files.is_some() { &header[..] } else { &header[1..] };
+		writer.write_record(header).unwrap_or_else(|err| write_failed(path, err));
+		Rejects {
+			path: path.to_string(),
+			writer,
+			files,
+			counts: BTreeMap::new(),
+			pending: BTreeMap::new(),
+			ready: BTreeMap::new(),
+		}
+	}
+
+	fn submitted(&mut self, seq: u64, row: Row) {
+		self.pending.insert(seq, row);
+	}
+
+	fn done(&mut self, seq: u64, result: Result<Outcome, kraken::Error>) {
+		let row = self.pending.remove(&seq).expect("result for a row that wasn't submitted");
+		match result {
+			Ok(_) => self.write_ready(),
+			Err(err) => self.reject(seq, row, Reject::Store(err)),
+		}
+	}
+
+	fn reject(&mut self, seq: u64, row: Row, reject: Reject) {
+		self.ready.insert(seq, (row, reject.code()));
+		self.write_ready();
+	}
+
+	fn write_ready(&mut self) {
+		let first_pending = self.pending.keys().next().copied().unwrap_or(u64::MAX);
+		while self.ready.keys().next().is_some_and(|&seq| seq < first_pending) {
+			let (_, (row, reason)) = self.ready.pop_first().unwrap();
+			let line = row.line.to_string();
+			let fields = [line.as_str(), &row.tx_type, &row.client, &row.tx, &row.amount, reason];
+			match self.files {
+				Some(ref files) => self.writer.write_field(&files[row.file]),
+				None => Ok(()),
+			}.and_then(|()| self.writer.write_record(fields)).unwrap_or_else(|err| write_failed(&self.path, err));
+			*self.counts.entry(reason).or_default() += 1;
+		}
+	}
+
+	/// Write out the last rows, giving back the number of rows per reason
+	fn finish(mut self) -> BTreeMap<&'static str, u64> {
+		self.write_ready();
+		self.writer.flush().unwrap_or_else(|err| write_failed(&self.path, err));
+		self.counts
+	}
+}
+
+/// Exit codes, besides 0 for success
+const EXIT_IO: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_PARSE: i32 = 3;
+const EXIT_INVARIANT: i32 = 4;
+
+/// Print an error and exit with the given code
+fn fail(code: i32, msg: impl fmt::Display) -> ! {
+	eprintln!("{}", msg);
+	process::exit(code);
+}
+
+/// Exit for an input that couldn't be read
+fn read_failed(err: csv::Error) -> ! {
+	match err.kind() {
+		csv::ErrorKind::Io(io) if io.kind() != io::ErrorKind::InvalidData => {
+			fail(EXIT_IO, format!("error reading input: {}", err))
+		},
+		_ => fail(EXIT_PARSE, format!("error parsing input: {}", err)),
+	}
+}
+
+/// Exit for an output that couldn't be written
+fn write_failed(path: &str, err: impl fmt::Display) -> ! {
+	fail(EXIT_IO, format!("error writing {}: {}", path, err))
+}
+
+/// The number of history pages kept in memory when spilling, about 240MB
+const SPILL_RESIDENT_PAGES: usize = 16 * 1024;
+
+fn main() {
+	let args = parse_args();
+
+	let inputs = match args.mode {
+		Mode::Process { ref inputs } => inputs,
+		Mode::Validate { ref inputs } => {
+			guard_panics();
+			validate(&args, inputs);
+			return;
+		},
+		Mode::Replay { ref dir } => {
+			guard_panics();
+			let store = open_state(&args, dir);
+			print_accounts(store.store().list_accounts_by(args.sort), args.output_format);
+			return;
+		},
+		Mode::Inspect { client, ref inputs } => {
+			guard_panics();
+			match args.state {
+				Some(ref dir) => {
+					let store = process(&args, inputs, open_state(&args, dir));
+					print_client(store.store(), client, args.output_format);
+				},
+				None => print_client(&process(&args, inputs, args.configure(Store::new())), client, args.output_format),
+			}
+			return;
+		},
+		Mode::Statement { client, ref inputs } => {
+			guard_panics();
+			let store = process(&args, inputs, args.configure(Store::new().with_event_log()));
+			print_statement(store.statement(client), args.output_format);
+			return;
+		},
+		Mode::ExportSql { ref dir } => {
+			guard_panics();
+			if !std::path::Path::new(dir).is_dir() {
+				fail(EXIT_IO, format!("no tables in {}", dir));
+			}
+			let backend = FileBackend::open(dir).unwrap_or_else(|err| fail(EXIT_IO, format!("failed to open the tables in {}: {}", dir, err)));
+			let stdout = io::stdout();
+			if let Err(err) = backend.write_sql(io::BufWriter::new(stdout.lock())) {
+				write_failed("stdout", err);
+			}
+			return;
+		},
+		Mode::Serve { ref addr } | Mode::Http { ref addr } => {
+			let store = args.configure(Store::new());
+			let served = match args.mode {
+				Mode::Http { .. } => http::serve(addr, store),
+				_ => serve::serve(addr, store),
+			};
+			if let Err(err) = served {
+				fail(EXIT_IO, format!("failed to serve on {}: {}", addr, err));
+			}
+			return;
+		},
+	};
+	guard_panics();
+
+	if let Some(ref dir) = args.state {
+		let store = process(&args, inputs, open_state(&args, dir));
+		print_accounts(store.store().list_accounts_by(args.sort), args.output_format);
+		return;
+	}
+
+	if let Some(ref dir) = args.tables {
+		let backend = FileBackend::create(dir).unwrap_or_else(|err| write_failed(dir, err));
+		let store = process(&args, inputs, args.configure(Store::with_backend(backend)));
+		print_accounts(store.list_accounts_by(args.sort), args.output_format);
+		return;
+	}
+
+	// the compact backend only when asked for, it's slower for sparse txids
+	if args.retention.is_none() && args.spill.is_none() {
+		let store = || args.configure(Store::new());
+		if args.threads > 1 {
+			let store = process(&args, inputs, ShardedStore::with_stores(args.threads, store));
+			print_accounts(store.list_accounts_by(args.sort).into_iter(), args.output_format);
+		} else {
+			print_accounts(process(&args, inputs, store()).list_accounts_by(args.sort), args.output_format);
+		}
+		return;
+	}
+
+	// --retention and --spill are refused with --threads
+	let mut backend = CompactBackend::new();
+	if let Some(retention) = args.retention {
+		backend = backend.with_retention(retention);
+	}
+	if let Some(ref path) = args.spill {
+		backend = backend
+			.with_spill_file(path, SPILL_RESIDENT_PAGES)
+			.unwrap_or_else(|err| write_failed(path, err));
+	}
+	let store = process(&args, inputs, args.configure(Store::with_backend(backend)));
+	print_accounts(store.list_accounts_by(args.sort), args.output_format);
+}
+
+/// Exit with `EXIT_INVARIANT` on a panic, which can only be a bug
+fn guard_panics() {
+	let default_hook = panic::take_hook();
+	panic::set_hook(Box::new(move |info| {
+		default_hook(info);
+		process::exit(EXIT_INVARIANT);
+	}));
+}
+
+/// Open the persistent store in `dir`
+fn open_state(args: &Args, dir: &str) -> PersistentStore<FileStorage> {
+	let store = match FileStorage::open(dir, args.configure(Store::new())) {
+		Ok(store) => store,
+		// a crash can't cause this: log entries are synced one by one and a
+		// partial last one is dropped, and snapshots are synced before they
+		// are renamed into place. The files were damaged or edited.
+		Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+			fail(EXIT_INVARIANT, format!("the state in {} is inconsistent: {}", dir, err))
+		},
+		Err(err) => fail(EXIT_IO, format!("failed to open the state in {}: {}", dir, err)),
+	};
+	match args.snapshot_interval {
+		Some(interval) => store.with_snapshot_interval(interval),
+		None => store,
+	}
+}
+
+/// Open all inputs
+fn open_rows(args: &Args, inputs: &[String]) -> Rows {
+	Rows {
+		sources: inputs.iter().map(|path| Source::open(path, args.input_format)).collect(),
+		merge: args.merge,
+		last: None,
+	}
+}
+
+/// Apply all rows of the inputs
+fn process<E: Engine>(args: &Args, inputs: &[String], mut engine: E) -> E::State {
+	let mut rows = open_rows(args, inputs);
+
+	// the spec says failed txs are ignored, so they only get reported if asked for
+	let mut rejects = args.rejects.as_deref().map(|path| Rejects::create(path, inputs));
+	let report = |rejects: &mut Option<Rejects>, seq, result: Result<Outcome, kraken::Error>| {
+		// a backend that can't save is an I/O failure, not a rejected row
+		if let Err(kraken::Error::Storage { ref message }) = result {
+			fail(EXIT_IO, format!("error saving tx {}: {}", seq, message));
+		}
+		if let Some(ref mut rejects) = rejects {
+			rejects.done(seq, result);
+		}
+	};
+
+	// rows are numbered in the order they are applied
+	let mut seq = 0;
+	let mut current: Option<usize> = None;
+	while let Some(file) = rows.next() {
+		// merged inputs only end together
+		if let Some(last) = current.filter(|&last| last != file && !args.merge) {
+			check_input(&engine, args, &inputs[last]);
+		}
+		current = Some(file);
+		let source = &rows.sources[file];
+		let tx = parse(&source.record, &source.headers);
+		seq += 1;
+
+		// the fields are only kept around when there's a report to write
+		if let Some(ref mut rejects) = rejects {
+			let row = source.row(file);
+			match tx {
+				Ok(_) => rejects.submitted(seq, row),
+				Err(reject) => {
+					rejects.reject(seq, row, reject);
+					continue;
+				},
+			}
+		}
+		if let Ok(tx) = tx {
+			engine.submit(seq, tx, &mut |seq, result| report(&mut rejects, seq, result));
+			if args.paranoid {
+				check_audit(&engine, &inputs[file], source, tx);
+			}
+		}
+	}
+	if let Some(last) = current {
+		check_input(&engine, args, if args.merge { "all inputs" } else { &inputs[last] });
+	}
+	let state = engine.finish(&mut |seq, result| report(&mut rejects, seq, result));
+
+	if let Some(rejects) = rejects {
+		let counts = rejects.finish();
+		eprintln!("rejected {} rows", counts.values().sum::<u64>());
+		print_counts(&counts);
+	}
+	state
+}
+
+/// The checks at the end of an input: the trial balance, and with
+/// `--paranoid` the audit of all accounts
+fn check_input<E: Engine>(engine: &E, args: &Args, after: &str) {
+	check_balance(engine, after);
+	if !args.paranoid {
+		return;
+	}
+	match engine.audit() {
+		Some(violations) if !violations.is_empty() => {
+			eprintln!("invariant violated after {}", after);
+			exit_violations(&violations);
+		},
+		_ => {},
+	}
+}
+
+/// Check that the journal sums to zero and agrees with the accounts, if
+/// there is one, and exit with `EXIT_INVARIANT` if not
+fn check_balance<E: Engine>(engine: &E, after: &str) {
+	let balance = match engine.trial_balance() {
+		Some(balance) => balance,
+		None => return,
+	};
+	if balance.is_balanced() {
+		eprintln!("trial balance after {}: ok", after);
+		return;
+	}
+	eprintln!("trial balance after {}: off by {}", after, balance.total);
+	for m in &balance.mismatches {
+		eprintln!("  {}: {} in the journal, {} in the account", m.account, m.journal, m.store);
+	}
+	process::exit(EXIT_INVARIANT);
+}
+
+/// Audit the accounts a tx touched, and exit with `EXIT_INVARIANT` at the
+/// first violation, with everything known about it
+fn check_audit<E: Engine>(engine: &E, path: &str, source: &Source, tx: Transaction) {
+	let violations = match engine.audit_tx(&tx) {
+		Some(violations) if !violations.is_empty() => violations,
+		_ => return,
+	};
+	let record = source.record.iter().collect::<Vec<_>>().join(",");
+	eprintln!("invariant violated after {}:{}: {}", path, source.line.unwrap_or(0), record);
+	eprintln!("  tx: {:?}", tx);
+	exit_violations(&violations);
+}
+
+fn exit_violations(violations: &[Violation]) -> ! {
+	for violation in violations {
+		eprintln!("  {}", violation);
+		eprintln!("    {:?}", violation);
+	}
+	process::exit(EXIT_INVARIANT);
+}
+
+/// Check that all rows parse and have valid amounts, without applying them
+fn validate(args: &Args, inputs: &[String]) {
+	let mut rows = open_rows(args, inputs);
+	let mut rejects = args.rejects.as_deref().map(|path| Rejects::create(path, inputs));
+	let mut counts = BTreeMap::<&'static str, u64>::new();
+
+	let mut seq = 0;
+	while let Some(file) = rows.next() {
+		let source = &rows.sources[file];
+		seq += 1;
+		let reject = match parse(&source.record, &source.headers) {
+			Ok(Transaction::Deposit { amount, .. }) | Ok(Transaction::Withdrawal { amount, .. }) => {
+				match validate_amount(amount) {
+					Ok(()) => continue,
+					Err(err) => Reject::Store(err),
+				}
+			},
+			Ok(_) => continue,
+			Err(reject) => reject,
+		};
+		*counts.entry(reject.code()).or_default() += 1;
+		if let Some(ref mut rejects) = rejects {
+			rejects.reject(seq, source.row(file), reject);
+		}
+	}
+	if let Some(rejects) = rejects {
+		rejects.finish();
+	}
+
+	let invalid = counts.values().sum::<u64>();
+	eprintln!("{} of {} rows are invalid", invalid, seq);
+	print_counts(&counts);
+	if invalid > 0 {
+		process::exit(EXIT_PARSE);
+	}
+}
+
+fn print_counts(counts: &BTreeMap<&'static str, u64>) {
+	for (reason, count) in counts {
+		eprintln!("  {}: {}", reason, count);
+	}
+}
+
+/// Print all account summaries
+fn print_accounts(accounts: impl Iterator<Item = AccountSummary>, format: OutputFormat) {
+	let stdout = io::stdout();
+	let mut outputs = accounts.map(OutputLine::from);
+	let written = match format {
+		OutputFormat::Csv => {
+			let mut writer = csv::WriterBuilder::new()
+				.buffer_capacity(1024 * 1024)
+				.delimiter(b',')
+				.has_headers(true)
+				.from_writer(stdout.lock());
+			outputs
+				.try_for_each(|output| writer.serialize(output))
+				.and_then(|()| Ok(writer.flush()?))
+				.map_err(|err| err.to_string())
+		},
+		OutputFormat::Jsonl => {
+			let mut writer = io::BufWriter::new(stdout.lock());
+			outputs
+				.try_for_each(|output| writeln!(writer, "{}", output.to_json()))
+				.and_then(|()| writer.flush())
+				.map_err(|err| err.to_string())
+		},
+		OutputFormat::Json => {
+			let outputs = outputs.map(|o| format!("\n  {}", o.to_json())).collect::<Vec<_>>();
+			let end = if outputs.is_empty() { "]" } else { "\n]" };
+			writeln!(stdout.lock(), "[{}{}", outputs.join(","), end).map_err(|err| err.to_string())
+		},
+	};
+	written.unwrap_or_else(|err| write_failed("stdout", err));
+}
+
+/// Print the account of a client and its deposits and withdrawals
+fn print_client(store: &Store, client: ClientId, format: OutputFormat) {
+	let account = store.list_accounts().find(|a| a.client == client).map(OutputLine::from);
+	let mut txs = store.transactions().filter(|tx| tx.client == client).collect::<Vec<_>>();
+	txs.sort_by_key(|tx| tx.txid);
+
+	let mut out = String::new();
+	match format {
+		OutputFormat::Csv => {
+			out += "client,available,held,total,locked\n";
+			if let Some(a) = account {
+				out += &format!("{},{},{},{},{}\n", a.client, a.available, a.held, a.total, a.locked);
+			}
+			out += "\ntx,type,amount,state\n";
+			for tx in txs {
+				out += &format!("{},{},{},{}\n", tx.txid, tx.tp.name(), tx.amount, tx.dispute_state.name());
+			}
+		},
+		OutputFormat::Jsonl => {
+			if let Some(a) = account {
+				out += &format!("{}\n", a.to_json());
+			}
+			for tx in txs {
+				out += &format!("{}\n", tx_json(&tx));
+			}
+		},
+		OutputFormat::Json => {
+			let account = account.map_or("null".to_string(), |a| a.to_json());
+			let txs = txs.iter().map(tx_json).collect::<Vec<_>>();
+			out += &format!("{{\"account\":{},\"transactions\":[{}]}}\n", account, txs.join(","));
+		},
+	}
+	io::stdout().write_all(out.as_bytes()).unwrap_or_else(|err| write_failed("stdout", err));
+}
+
+fn statement_json(line: &StatementLine) -> String {
+	let b = &line.balance;
+	format!(
+		r#"{{"seq":{},"type":"{}","tx":{},"amount":{},"available":{},"held":{},"total":{},"locked":{}}}"#,
+		line.seq, line.tx_type.name(), line.txid, line.amount, b.available, b.held, b.total, b.locked,
+	)
+}
+
+/// Print the operations on a client's account with the running balance
+fn print_statement(lines: Vec<StatementLine>, format: OutputFormat) {
+	let mut out = String::new();
+	match format {
+		OutputFormat::Csv => {
+			out += "seq,type,tx,amount,available,held,total,locked\n";
+			for line in &lines {
+				let b = &line.balance;
+				out += &format!(
+					"{},{},{},{},{},{},{},{}\n",
+					line.seq, line.tx_type.name(), line.txid, line.amount, b.available, b.held, b.total, b.locked,
+				);
+			}
+		},
+		OutputFormat::Jsonl => {
+			for line in &lines {
+				out += &format!("{}\n", statement_json(line));
+			}
+		},
+		OutputFormat::Json => {
+			let lines = lines.iter().map(|l| format!("\n  {}", statement_json(l))).collect::<Vec<_>>();
+			let end = if lines.is_empty() { "]" } else { "\n]" };
+			out += &format!("[{}{}\n", lines.join(","), end);
+		},
 	}
+	io::stdout().write_all(out.as_bytes()).unwrap_or_else(|err| write_failed("stdout", err));
 }
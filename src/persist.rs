@@ -0,0 +1,546 @@
+
+//! Persistence for the `Store`, so that processing can continue after a
+//! crash instead of starting over.
+//!
+//! Every transaction is appended to a write-ahead log before it's applied,
+//! and every so often a snapshot of the whole state is written, after which
+//! the log starts over. Recovering is loading the snapshot and replaying the
+//! log on top of it. Since the store is deterministic, this gives back the
+//! exact account and dispute state, as long as the store is reopened with
+//! the same policies; txs it refused are refused again.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use rust_decimal::Decimal;
+
+use crate::store::{AccountSummary, Error, Outcome, Store, Transaction, TxRecord, TxType};
+
+const SNAPSHOT_FILE: &str = "snapshot";
+const SNAPSHOT_TMP_FILE: &str = "snapshot.tmp";
+const LOG_FILE: &str = "wal.log";
+const SNAPSHOT_HEADER: &str = "kraken-snapshot 1";
+
+/// Where a `PersistentStore` keeps its state
+pub trait Storage {
+	/// Record a tx before it's applied, with its sequence number. It must
+	/// survive a crash once this returns.
+	fn append(&mut self, seq: u64, tx: &Transaction) -> io::Result<()>;
+
+	/// Save the whole state of the store, which includes all txs up to and
+	/// including `seq`. The log up to there is no longer needed after this.
+	fn snapshot(&mut self, seq: u64, store: &Store) -> io::Result<()>;
+}
+
+/// Storage that doesn't keep anything, for when persistence isn't wanted
+#[derive(Debug, Default)]
+pub struct NoStorage;
+
+impl Storage for NoStorage {
+	fn append(&mut self, _seq: u64, _tx: &Transaction) -> io::Result<()> {
+		Ok(())
+	}
+
+	fn snapshot(&mut self, _seq: u64, _store: &Store) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+/// Storage in a directory, with a write-ahead log and a snapshot file.
+///
+/// Every log entry is synced to disk on its own, so the throughput is
+/// bound by how fast the disk syncs.
+#[derive(Debug)]
+pub struct FileStorage {
+	dir: PathBuf,
+	log: File,
+}
+
+/// Shorthand for corrupt data errors
+fn invalid(msg: String) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Sync a directory, so that a rename in it survives a crash
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+	File::open(dir)?.sync_all()
+}
+
+/// Directories can't be opened to sync them here, renames are as durable
+/// as the platform makes them
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+	Ok(())
+}
+
+fn storage_failed(err: Error) -> io::Error {
+	io::Error::other(err.to_string())
+}
+
+fn format_tx(seq: u64, tx: &Transaction) -> String {
+	match *tx {
+		Transaction::Deposit { txid, client, amount } | Transaction::Withdrawal { txid, client, amount } => {
+			format!("{} {} {} {} {}\n", seq, tx.tx_type().name(), client, txid, amount)
+		},
+		_ => format!("{} {} {} {}\n", seq, tx.tx_type().name(), tx.client(), tx.txid()),
+	}
+}
+
+fn parse_tx(line: &str) -> Option<(u64, Transaction)> {
+	let mut fields = line.split(' ');
+	let seq = fields.next()?.parse().ok()?;
+	let tp = fields.next()?.parse().ok()?;
+	let client = fields.next()?.parse().ok()?;
+	let txid = fields.next()?.parse().ok()?;
+	let mut amount = || fields.next()?.parse::<Decimal>().ok();
+	let tx = match tp {
+		TxType::Deposit => Transaction::Deposit { txid, client, amount: amount()? },
+		TxType::Withdrawal => Transaction::Withdrawal { txid, client, amount: amount()? },
+		TxType::Dispute => Transaction::Dispute { client, txid },
+		TxType::Resolve => Transaction::Resolve { client, txid },
+		TxType::Chargeback => Transaction::Chargeback { client, txid },
+	};
+	if fields.next().is_some() {
+		return None;
+	}
+	Some((seq, tx))
+}
+
+fn parse_account(mut fields: std::str::Split<char>) -> Option<AccountSummary> {
+	let client = fields.next()?.parse().ok()?;
+	let available: Decimal = fields.next()?.parse().ok()?;
+	let held: Decimal = fields.next()?.parse().ok()?;
+	let locked = fields.next()?.parse().ok()?;
+	Some(AccountSummary {
+		client,
+		available,
+		held,
+		total: available.checked_add(held)?,
+		locked,
+	})
+}
+
+fn parse_record(mut fields: std::str::Split<char>) -> Option<TxRecord> {
+	Some(TxRecord {
+		txid: fields.next()?.parse().ok()?,
+		// only deposits and withdrawals are stored
+		tp: fields.next()?.parse().ok().filter(|&tp| tp == TxType::Deposit || tp == TxType::Withdrawal)?,
+		client: fields.next()?.parse().ok()?,
+		amount: fields.next()?.parse().ok()?,
+		dispute_state: fields.next()?.parse().ok()?,
+	})
+}
+
+impl FileStorage {
+	/// Open the storage in the given directory, creating it if needed, and
+	/// recover its state into `store`, which should be a new store with the
+	/// same policies as the one that wrote it.
+	pub fn open<P: AsRef<Path>>(dir: P, store: Store) -> io::Result<PersistentStore<FileStorage>> {
+		let dir = dir.as_ref().to_path_buf();
+		fs::create_dir_all(&dir)?;
+
+		let mut store = store;
+		let snapshot_seq = FileStorage::load_snapshot(&dir, &mut store)?;
+		let (seq, log_len) = FileStorage::replay_log(&dir, snapshot_seq, &mut store)?;
+
+		// drop a partially written tail, so new entries start on a fresh line
+		let log = OpenOptions::new().create(true).append(true).open(dir.join(LOG_FILE))?;
+		log.set_len(log_len)?;
+		// a newly created log has to survive a crash like its entries
+		sync_dir(&dir)?;
+
+		Ok(PersistentStore::new(store, FileStorage { dir, log }, seq))
+	}
+
+	/// Load the snapshot, if there is one, and return its sequence number
+	fn load_snapshot(dir: &Path, store: &mut Store) -> io::Result<u64> {
+		let file = match File::open(dir.join(SNAPSHOT_FILE)) {
+			Ok(file) => file,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+			Err(e) => return Err(e),
+		};
+
+		let mut lines = BufReader::new(file).lines();
+		if lines.next().transpose()?.as_deref() != Some(SNAPSHOT_HEADER) {
+			return Err(invalid("not a snapshot file".into()));
+		}
+
+		let mut seq = None;
+		let mut complete = false;
+		for line in lines {
+			let line = line?;
+			let mut fields = line.split(' ');
+			let ok = match fields.next() {
+				Some("seq") => {
+					seq = fields.next().and_then(|s| s.parse().ok());
+					seq.is_some()
+				},
+				Some("account") => match parse_account(fields) {
+					Some(a) => store.restore_account(&a).map(|_| true).map_err(storage_failed)?,
+					None => false,
+				},
+				Some("tx") => match parse_record(fields) {
+					Some(r) => store.restore_transaction(&r).map(|_| true).map_err(storage_failed)?,
+					None => false,
+				},
+				Some("end") => {
+					complete = true;
+					true
+				},
+				_ => false,
+			};
+			if !ok {
+				return Err(invalid(format!("invalid snapshot line: {:?}", line)));
+			}
+		}
+
+		// snapshots are renamed into place once written, so this is never
+		// expected to happen
+		match (seq, complete) {
+			(Some(seq), true) => Ok(seq),
+			_ => Err(invalid("incomplete snapshot".into())),
+		}
+	}
+
+	/// Replay the log on top of the snapshot. Returns the last sequence
+	/// number and the length of the log up to its last complete entry.
+	fn replay_log(dir: &Path, snapshot_seq: u64, store: &mut Store) -> io::Result<(u64, u64)> {
+		let file = match File::open(dir.join(LOG_FILE)) {
+			Ok(file) => file,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((snapshot_seq, 0)),
+			Err(e) => return Err(e),
+		};
+
+		let mut reader = BufReader::new(file);
+		let mut seq = snapshot_seq;
+		let mut len = 0;
+		let mut line = String::new();
+		loop {
+			line.clear();
+			let n = reader.read_line(&mut line)?;
+			// a missing newline means we crashed while writing this entry
+			if n == 0 || !line.ends_with('\n') {
+				break;
+			}
+
+			let (entry_seq, tx) = parse_tx(line.trim_end())
+				.ok_or_else(|| invalid(format!("invalid log line: {:?}", line)))?;
+			// the entries up to the snapshot may still be there if we crashed
+			// between writing the snapshot and truncating the log
+			if entry_seq > snapshot_seq {
+				if entry_seq != seq + 1 {
+					return Err(invalid(format!("log entry {} follows {}", entry_seq, seq)));
+				}
+				// refused txs are logged too, and refused again
+				if let Err(Error::Storage { message }) = store.apply(tx) {
+					return Err(io::Error::other(message));
+				}
+				seq = entry_seq;
+			}
+			len += n as u64;
+		}
+		Ok((seq, len))
+	}
+}
+
+impl Storage for FileStorage {
+	fn append(&mut self, seq: u64, tx: &Transaction) -> io::Result<()> {
+		// a single write, so a crash leaves at most one partial entry
+		self.log.write_all(format_tx(seq, tx).as_bytes())?;
+		self.log.sync_data()
+	}
+
+	fn snapshot(&mut self, seq: u64, store: &Store) -> io::Result<()> {
+		let tmp_path = self.dir.join(SNAPSHOT_TMP_FILE);
+		let mut out = BufWriter::new(File::create(&tmp_path)?);
+		writeln!(out, "{}", SNAPSHOT_HEADER)?;
+		writeln!(out, "seq {}", seq)?;
+		for a in store.exact_accounts() {
+			writeln!(out, "account {} {} {} {}", a.client, a.available, a.held, a.locked)?;
+		}
+		for tx in store.transactions() {
+			writeln!(out, "tx {} {} {} {} {}",
+				tx.txid, tx.tp.name(), tx.client, tx.amount, tx.dispute_state.name(),
+			)?;
+		}
+		writeln!(out, "end")?;
+		let file = out.into_inner().map_err(|e| e.into_error())?;
+		file.sync_all()?;
+
+		// the log is only dropped once the new snapshot is sure to be found
+		fs::rename(&tmp_path, self.dir.join(SNAPSHOT_FILE))?;
+		sync_dir(&self.dir)?;
+		self.log.set_len(0)?;
+		Ok(())
+	}
+}
+
+/// A `Store` that records everything it accepts in a `Storage`
+#[derive(Debug)]
+pub struct PersistentStore<S: Storage> {
+	store: Store,
+	storage: S,
+	/// The sequence number of the last logged tx
+	seq: u64,
+	/// The number of txs after which a snapshot is taken
+	snapshot_interval: u64,
+	/// The number of txs since the last snapshot
+	since_snapshot: u64,
+}
+
+impl<S: Storage> PersistentStore<S> {
+	pub fn new(store: Store, storage: S, seq: u64) -> PersistentStore<S> {
+		PersistentStore {
+			store,
+			storage,
+			seq,
+			snapshot_interval: 10_000,
+			since_snapshot: 0,
+		}
+	}
+
+	/// Set the number of txs after which a snapshot is taken
+	pub fn with_snapshot_interval(mut self, interval: u64) -> PersistentStore<S> {
+		self.snapshot_interval = interval;
+		self
+	}
+
+	/// The store with the current state
+	pub fn store(&self) -> &Store {
+		&self.store
+	}
+
+	/// The sequence number of the last logged tx
+	pub fn seq(&self) -> u64 {
+		self.seq
+	}
+
+	/// Record a tx and apply it to the store. The outer error is a storage
+	/// failure, the inner one is the store refusing it.
+	pub fn apply(&mut self, tx: Transaction) -> io::Result<Result<Outcome, Error>> {
+		// whether the store accepts it is only known after applying it, by
+		// then the log must already have it
+		self.storage.append(self.seq + 1, &tx)?;
+		self.seq += 1;
+		self.since_snapshot += 1;
+		let result = self.store.apply(tx);
+		if self.since_snapshot >= self.snapshot_interval {
+			self.checkpoint()?;
+		}
+		Ok(result)
+	}
+
+	/// Take a snapshot now
+	pub fn checkpoint(&mut self) -> io::Result<()> {
+		self.storage.snapshot(self.seq, &self.store)?;
+		self.since_snapshot = 0;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::store::{DisputePolicy, DisputeState};
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	/// Helper to create a decimal.
+	fn d(s: &str) -> Decimal {
+		s.parse().expect("invalid decimal")
+	}
+
+	/// A fresh directory for a test
+	fn temp_dir(name: &str) -> PathBuf {
+		static COUNTER: AtomicUsize = AtomicUsize::new(0);
+		let dir = std::env::temp_dir().join(format!("kraken-persist-{}-{}-{}",
+			std::process::id(), name, COUNTER.fetch_add(1, Ordering::SeqCst),
+		));
+		let _ = fs::remove_dir_all(&dir);
+		dir
+	}
+
+	fn txs() -> Vec<Transaction> {
+		vec![
+			Transaction::Deposit { txid: 1, client: 1, amount: d("10.5") },
+			Transaction::Deposit { txid: 2, client: 2, amount: d("3") },
+			Transaction::Withdrawal { txid: 3, client: 1, amount: d("2.25") },
+			Transaction::Dispute { client: 2, txid: 2 },
+			Transaction::Dispute { client: 1, txid: 3 },
+			Transaction::Withdrawal { txid: 4, client: 2, amount: d("1") }, // refused
+			Transaction::Resolve { client: 1, txid: 3 },
+			Transaction::Deposit { txid: 5, client: 3, amount: d("1.0001") },
+			Transaction::Chargeback { client: 2, txid: 2 },
+		]
+	}
+
+	fn new_store() -> Store {
+		Store::new().with_dispute_policy(DisputePolicy::ByTxType)
+	}
+
+	/// The state of a store that can be compared
+	fn state(store: &Store) -> (Vec<AccountSummary>, Vec<TxRecord>) {
+		let mut txs = store.transactions().collect::<Vec<_>>();
+		txs.sort_by_key(|tx| tx.txid);
+		(store.exact_accounts().collect(), txs)
+	}
+
+	/// The state after applying txs to a store without persistence
+	fn expected(txs: &[Transaction]) -> (Vec<AccountSummary>, Vec<TxRecord>) {
+		let mut store = new_store();
+		for &tx in txs {
+			let _ = store.apply(tx);
+		}
+		state(&store)
+	}
+
+	fn apply_all(store: &mut PersistentStore<FileStorage>, txs: &[Transaction]) {
+		for &tx in txs {
+			let _ = store.apply(tx).unwrap();
+		}
+	}
+
+	#[test]
+	fn reopen() {
+		let dir = temp_dir("reopen");
+		let txs = txs();
+
+		let mut store = FileStorage::open(&dir, new_store()).unwrap();
+		apply_all(&mut store, &txs[..5]);
+		assert_eq!(store.seq(), 5);
+		drop(store);
+
+		let mut store = FileStorage::open(&dir, new_store()).unwrap();
+		assert_eq!(store.seq(), 5);
+		assert_eq!(state(store.store()), expected(&txs[..5]));
+		apply_all(&mut store, &txs[5..]);
+		assert_eq!(store.seq(), 9);
+		drop(store);
+
+		let store = FileStorage::open(&dir, new_store()).unwrap();
+		assert_eq!(state(store.store()), expected(&txs));
+		assert_eq!(store.store().get_transaction(2).unwrap().dispute_state, DisputeState::ChargedBack);
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn snapshots() {
+		let dir = temp_dir("snapshots");
+		let txs = txs();
+
+		let mut store = FileStorage::open(&dir, new_store()).unwrap().with_snapshot_interval(4);
+		apply_all(&mut store, &txs);
+		drop(store);
+		// 9 txs, the last snapshot was taken after 8
+		assert_eq!(fs::read_to_string(dir.join(LOG_FILE)).unwrap().lines().count(), 1);
+
+		let store = FileStorage::open(&dir, new_store()).unwrap();
+		assert_eq!(store.seq(), 9);
+		assert_eq!(state(store.store()), expected(&txs));
+
+		// a journal starts from the snapshot and follows the log
+		let store = FileStorage::open(&dir, new_store().with_journal()).unwrap();
+		assert!(store.store().trial_balance().unwrap().is_balanced());
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn crash_before_log_truncation() {
+		let dir = temp_dir("crash-truncation");
+		let txs = txs();
+
+		let mut store = FileStorage::open(&dir, new_store()).unwrap();
+		apply_all(&mut store, &txs[..5]);
+		let log = fs::read(dir.join(LOG_FILE)).unwrap();
+		store.checkpoint().unwrap();
+		apply_all(&mut store, &txs[5..]);
+		drop(store);
+
+		// put back the entries the snapshot already covers
+		let mut full_log = log;
+		full_log.extend(fs::read(dir.join(LOG_FILE)).unwrap());
+		fs::write(dir.join(LOG_FILE), full_log).unwrap();
+
+		let store = FileStorage::open(&dir, new_store()).unwrap();
+		assert_eq!(store.seq(), 9);
+		assert_eq!(state(store.store()), expected(&txs));
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn truncated_log_tail() {
+		let txs = txs();
+		let dir = temp_dir("truncated-base");
+		let mut store = FileStorage::open(&dir, new_store()).unwrap();
+		apply_all(&mut store, &txs);
+		drop(store);
+		let log = fs::read(dir.join(LOG_FILE)).unwrap();
+		fs::remove_dir_all(&dir).unwrap();
+
+		// cut the log at every possible length
+		for len in 0..=log.len() {
+			let dir = temp_dir("truncated");
+			fs::create_dir_all(&dir).unwrap();
+			fs::write(dir.join(LOG_FILE), &log[..len]).unwrap();
+
+			let complete = log[..len].iter().filter(|&&b| b == b'\n').count();
+			let mut store = FileStorage::open(&dir, new_store()).unwrap();
+			assert_eq!(store.seq(), complete as u64);
+
+			// the store must have the state of the txs in the complete entries
+			assert_eq!(state(store.store()), expected(&txs[..complete]), "log cut at {}", len);
+
+			// and continue from there, on a clean line
+			let next = Transaction::Deposit { txid: 100, client: 9, amount: d("1") };
+			assert_eq!(store.apply(next).unwrap(), Ok(Outcome::Applied));
+			drop(store);
+			let store = FileStorage::open(&dir, new_store()).unwrap();
+			assert_eq!(store.seq(), complete as u64 + 1);
+			fs::remove_dir_all(&dir).unwrap();
+		}
+	}
+
+	/// Storage whose disk is full
+	struct FullStorage;
+
+	impl Storage for FullStorage {
+		fn append(&mut self, _seq: u64, _tx: &Transaction) -> io::Result<()> {
+			Err(io::Error::other("disk full"))
+		}
+
+		fn snapshot(&mut self, _seq: u64, _store: &Store) -> io::Result<()> {
+			Err(io::Error::other("disk full"))
+		}
+	}
+
+	#[test]
+	fn failed_append() {
+		let mut store = PersistentStore::new(new_store(), FullStorage, 0);
+		assert!(store.apply(txs()[0]).is_err());
+		assert_eq!(store.seq(), 0);
+		assert_eq!(store.store().list_accounts().count(), 0);
+	}
+
+	#[test]
+	fn corrupt_log() {
+		let dir = temp_dir("corrupt");
+		fs::create_dir_all(&dir).unwrap();
+		fs::write(dir.join(LOG_FILE), "1 deposit 1 1 5\ngarbage\n2 deposit 1 2 5\n").unwrap();
+		let err = FileStorage::open(&dir, new_store()).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+		// a gap in the sequence numbers means entries went missing
+		fs::write(dir.join(LOG_FILE), "1 deposit 1 1 5\n3 deposit 1 2 5\n").unwrap();
+		let err = FileStorage::open(&dir, new_store()).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+		// only deposits and withdrawals are stored
+		fs::remove_file(dir.join(LOG_FILE)).unwrap();
+		let snapshot = format!("{}\nseq 1\naccount 1 5 0 false\ntx 1 dispute 1 5 normal\nend\n", SNAPSHOT_HEADER);
+		fs::write(dir.join(SNAPSHOT_FILE), snapshot).unwrap();
+		let err = FileStorage::open(&dir, new_store()).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
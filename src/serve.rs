@@ -0,0 +1,154 @@
+//! The `serve` mode: a store shared by any number of connections over TCP
+//! or a Unix socket.
+//!
+//! Every connection sends requests, one per line, and gets one response
+//! per request, in order:
+//!
+//! - a CSV row `type,client,tx,amount` is applied and acknowledged with
+//!   `ok,<tx>` or `rejected,<tx>,<reason>`. A `type,client,tx,amount`
+//!   header line is accepted and skipped without a response.
+//! - a JSON object with the same fields is applied and acknowledged with
+//!   `{"tx":<tx>,"result":"ok"}` or
+//!   `{"tx":<tx>,"result":"rejected","reason":"<reason>"}`.
+//! - `accounts` lists all accounts and `account <client>` one of them, as
+//!   CSV with a header, ended by an empty line.
+//!
+//! The reasons are the same as in the rejects file.
+
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+
+use kraken::{AccountSummary, ClientId, Store};
+
+use crate::{json_row, parse, Reject, COLUMNS};
+
+/// Accept connections on `addr` until the process is stopped. Addresses
+/// starting with `unix:` are paths of Unix sockets.
+pub fn serve(addr: &str, store: Store) -> io::Result<()> {
+	let store = Arc::new(Mutex::new(store));
+
+	if let Some(path) = addr.strip_prefix("unix:") {
+		return serve_unix(path, &store);
+	}
+	let listener = TcpListener::bind(addr)?;
+	eprintln!("listening on {}", listener.local_addr()?);
+	for stream in listener.incoming() {
+		spawn(stream, &store, handle);
+	}
+	Ok(())
+}
+
+#[cfg(unix)]
+fn serve_unix(path: &str, store: &Arc<Mutex<Store>>) -> io::Result<()> {
+	let listener = UnixListener::bind(path)?;
+	eprintln!("listening on unix:{}", path);
+	for stream in listener.incoming() {
+		spawn(stream, store, handle);
+	}
+	Ok(())
+}
+
+#[cfg(not(unix))]
+fn serve_unix(_path: &str, _store: &Arc<Mutex<Store>>) -> io::Result<()> {
+	crate::fail(crate::EXIT_USAGE, "unix: addresses are only supported on Unix")
+}
+
+/// Lock the store, also after a connection panicked while holding it, so
+/// that one bad request doesn't take down every other connection
+pub fn lock(store: &Mutex<Store>) -> MutexGuard<'_, Store> {
+	store.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Handle a connection on its own thread
+pub fn spawn<S>(stream: io::Result<S>, store: &Arc<Mutex<Store>>, handle: fn(&S, &Mutex<Store>) -> io::Result<()>)
+where
+	S: Send + 'static,
+	for<'a> &'a S: Read + Write,
+{
+	let stream = match stream {
+		Ok(stream) => stream,
+		Err(err) => {
+			eprintln!("failed to accept connection: {}", err);
+			return;
+		},
+	};
+	let store = Arc::clone(store);
+	thread::spawn(move || {
+		// a client going away is its own business
+		let _ = handle(&stream, &store);
+	});
+}
+
+fn handle<S>(stream: &S, store: &Mutex<Store>) -> io::Result<()>
+where
+	for<'a> &'a S: Read + Write,
+{
+	let mut reader = BufReader::new(stream);
+	let mut writer = BufWriter::new(stream);
+	let mut line = String::new();
+	while reader.read_line(&mut line)? > 0 {
+		respond(line.trim(), store, &mut writer)?;
+		line.clear();
+		// batch the responses to pipelined requests
+		if reader.buffer().is_empty() {
+			writer.flush()?;
+		}
+	}
+	writer.flush()
+}
+
+fn respond(request: &str, store: &Mutex<Store>, out: &mut impl Write) -> io::Result<()> {
+	if request.is_empty() || request == COLUMNS.join(",") {
+		return Ok(());
+	}
+	if request == "accounts" {
+		let accounts = lock(store).list_accounts().collect::<Vec<_>>();
+		return write_accounts(out, &accounts);
+	}
+	if let Some(client) = request.strip_prefix("account ") {
+		let client = match client.trim().parse::<ClientId>() {
+			Ok(client) => client,
+			Err(_) => return writeln!(out, "rejected,,{}", Reject::Malformed.code()),
+		};
+		let accounts = lock(store).list_accounts().filter(|a| a.client == client).collect::<Vec<_>>();
+		return write_accounts(out, &accounts);
+	}
+
+	let headers = csv::StringRecord::from(&COLUMNS[..]);
+	if request.starts_with('{') {
+		let (tx, result) = match json_row(request) {
+			Some(record) => (record.get(2).unwrap_or("").to_string(), apply(&record, &headers, store)),
+			None => (String::new(), Err(Reject::Malformed)),
+		};
+		let tx = tx.parse::<u32>().map_or("null".to_string(), |tx| tx.to_string());
+		match result {
+			Ok(()) => writeln!(out, r#"{{"tx":{},"result":"ok"}}"#, tx),
+			Err(reject) => writeln!(out, r#"{{"tx":{},"result":"rejected","reason":"{}"}}"#, tx, reject.code()),
+		}
+	} else {
+		let record = request.split(',').map(str::trim).collect::<csv::StringRecord>();
+		let tx = record.get(2).unwrap_or("");
+		match apply(&record, &headers, store) {
+			Ok(()) => writeln!(out, "ok,{}", tx),
+			Err(reject) => writeln!(out, "rejected,{},{}", tx, reject.code()),
+		}
+	}
+}
+
+fn apply(record: &csv::StringRecord, headers: &csv::StringRecord, store: &Mutex<Store>) -> Result<(), Reject> {
+	let tx = parse(record, headers)?;
+	lock(store).apply(tx).map_err(Reject::Store)?;
+	Ok(())
+}
+
+fn write_accounts(out: &mut impl Write, accounts: &[AccountSummary]) -> io::Result<()> {
+	writeln!(out, "client,available,held,total,locked")?;
+	for a in accounts {
+		writeln!(out, "{},{},{},{},{}", a.client, a.available, a.held, a.total, a.locked)?;
+	}
+	writeln!(out)
+}
@@ -0,0 +1,288 @@
+//! Processing on several threads, with the accounts sharded by client id.
+//!
+//! Every shard is a `Store` of its own on a worker thread, and owns the
+//! accounts of the clients that map to it. Deposits and withdrawals go to
+//! the shard of their client. Disputes, resolves and chargebacks go to the
+//! shard of the client that owns the referenced tx, which is what the
+//! sequential store looks at too, so ownership checks and
+//! `OwnershipPolicy::ApplyToOwner` work the same.
+//!
+//! To route those, the router remembers which client first used each txid.
+//! The only case it can't decide on its own is a txid that is reused by a
+//! client on another shard: whether that's a duplicate depends on whether
+//! the first tx was accepted. It then asks the other shard, which answers
+//! after it has worked through everything queued before, so the outcome
+//! is the same as processing in order.
+//!
+//! Each shard only counts its own txs, so a `CompactBackend` with a
+//! retention window would expire txs at different points than a single
+//! store would. Sharding is only exact without retention.
+
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::thread::{self, JoinHandle};
+
+use crate::backend::{Backend, MemoryBackend};
+use crate::store::{AccountOrder, AccountSummary, ClientId, Error, Outcome, Store, Transaction, TxId};
+
+/// How many txs can be queued for a shard before the router waits
+const QUEUE_LEN: usize = 1024;
+
+/// The result of a tx, tagged with the id it was submitted with
+pub type TxResult = (u64, Result<Outcome, Error>);
+
+enum Message {
+	Apply(u64, Transaction),
+	/// Whether the shard has accepted the txid, answered in queue order
+	Has(TxId, SyncSender<bool>),
+}
+
+struct Shard<B> {
+	queue: SyncSender<Message>,
+	worker: JoinHandle<Store<B>>,
+}
+
+/// A store that applies txs on several threads
+pub struct ShardedStore<B: Backend = MemoryBackend> {
+	shards: Vec<Shard<B>>,
+	/// The client that first used each deposit or withdrawal txid. This
+	/// holds every txid, which is why the CLI refuses `--spill` and
+	/// `--retention` with `--threads`.
+	owners: HashMap<TxId, ClientId>,
+	results: Receiver<TxResult>,
+}
+
+impl ShardedStore {
+	/// Create a sharded store of memory-backed stores with default policies
+	pub fn new(threads: usize) -> ShardedStore {
+		ShardedStore::with_stores(threads, Store::new)
+	}
+}
+
+impl<B: Backend + Send + 'static> ShardedStore<B> {
+	/// Create a sharded store with `threads` shards, at least one. All the
+	/// stores should be set up with the same policies.
+	pub fn with_stores(threads: usize, mut make_store: impl FnMut() -> Store<B>) -> ShardedStore<B> {
+		let (done, results) = mpsc::channel();
+		let shards = (0..threads.max(1))
+			.map(|_| {
+				let (queue, messages) = mpsc::sync_channel(QUEUE_LEN);
+				let store = make_store();
+				let done = done.clone();
+				let worker = thread::spawn(move || run_shard(store, messages, done));
+				Shard { queue, worker }
+			})
+			.collect();
+
+		ShardedStore {
+			shards,
+			owners: HashMap::new(),
+			results,
+		}
+	}
+
+	fn shard_of(&self, client: ClientId) -> usize {
+		client as usize % self.shards.len()
+	}
+
+	fn send(&self, shard: usize, message: Message) {
+		self.shards[shard].queue.send(message).expect("shard worker died");
+	}
+
+	/// The client whose shard should get a deposit or withdrawal
+	fn claim(&mut self, txid: TxId, client: ClientId) -> ClientId {
+		let owner = match self.owners.get(&txid) {
+			Some(&owner) => owner,
+			None => {
+				self.owners.insert(txid, client);
+				return client;
+			},
+		};
+		if self.shard_of(owner) == self.shard_of(client) {
+			// that shard knows everything about the txid
+			return client;
+		}
+
+		let (reply, answer) = mpsc::sync_channel(1);
+		self.send(self.shard_of(owner), Message::Has(txid, reply));
+		if answer.recv().expect("shard worker died") {
+			// let the owner's shard reject it as a duplicate
+			owner
+		} else {
+			self.owners.insert(txid, client);
+			client
+		}
+	}
+
+	/// Queue a tx. Its result comes back from `results` or `finish`,
+	/// tagged with `id`.
+	pub fn apply(&mut self, id: u64, tx: Transaction) {
+		let client = match tx {
+			Transaction::Deposit { txid, client, .. } | Transaction::Withdrawal { txid, client, .. } => {
+				self.claim(txid, client)
+			},
+			Transaction::Dispute { client, txid }
+			| Transaction::Resolve { client, txid }
+			| Transaction::Chargeback { client, txid } => {
+				self.owners.get(&txid).copied().unwrap_or(client)
+			},
+		};
+		self.send(self.shard_of(client), Message::Apply(id, tx));
+	}
+
+	/// The results that are ready so far, in no particular order. They
+	/// pile up until taken.
+	pub fn results(&self) -> impl Iterator<Item = TxResult> + '_ {
+		self.results.try_iter()
+	}
+
+	/// Wait for all queued txs and stop the workers. Gives back the results
+	/// that weren't taken yet and the final state.
+	pub fn finish(self) -> (Vec<TxResult>, Shards<B>) {
+		let stores = self.shards
+			.into_iter()
+			.map(|shard| {
+				drop(shard.queue);
+				shard.worker.join().expect("shard worker panicked")
+			})
+			.collect();
+		(self.results.into_iter().collect(), Shards { stores })
+	}
+}
+
+fn run_shard<B: Backend>(mut store: Store<B>, messages: Receiver<Message>, done: Sender<TxResult>) -> Store<B> {
+	for message in messages {
+		match message {
+			Message::Apply(id, tx) => {
+				// nobody listening anymore is fine, the state is still wanted
+				let _ = done.send((id, store.apply(tx)));
+			},
+			Message::Has(txid, reply) => {
+				let _ = reply.send(store.get_transaction(txid).is_some());
+			},
+		}
+	}
+	store
+}
+
+/// The stores of a `ShardedStore` after processing
+pub struct Shards<B: Backend = MemoryBackend> {
+	stores: Vec<Store<B>>,
+}
+
+impl<B: Backend> Shards<B> {
+	/// The store of every shard
+	pub fn stores(&self) -> &[Store<B>] {
+		&self.stores
+	}
+
+	/// List all accounts of all shards, like `Store::list_accounts`
+	pub fn list_accounts(&self) -> Vec<AccountSummary> {
+		self.list_accounts_by(AccountOrder::Client)
+	}
+
+	/// List all accounts of all shards, like `Store::list_accounts_by`
+	pub fn list_accounts_by(&self, order: AccountOrder) -> Vec<AccountSummary> {
+		let precision = match self.stores.first() {
+			Some(store) => store.precision(),
+			None => return Vec::new(),
+		};
+		let mut accounts = self.stores.iter().flat_map(|s| s.backend().accounts()).collect::<Vec<_>>();
+		accounts.sort_by_key(|a| a.id);
+		order.sort(&mut accounts);
+		accounts.into_iter().map(|a| a.summary().rounded(precision)).collect()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::store::OwnershipPolicy;
+	use rust_decimal::Decimal;
+
+	/// Run txs through a single store and a sharded one, and check that
+	/// every result and the final accounts are the same
+	fn compare(txs: &[Transaction], threads: usize, make_store: impl Fn() -> Store) {
+		let mut single = make_store();
+		let expected = txs.iter().map(|&tx| single.apply(tx)).collect::<Vec<_>>();
+
+		let mut sharded = ShardedStore::with_stores(threads, &make_store);
+		for (id, &tx) in txs.iter().enumerate() {
+			sharded.apply(id as u64, tx);
+		}
+		let (mut results, shards) = sharded.finish();
+		results.sort_by_key(|&(id, _)| id);
+		let results = results.into_iter().map(|(_, r)| r).collect::<Vec<_>>();
+
+		assert_eq!(results, expected);
+		for &order in &[AccountOrder::Client, AccountOrder::Total, AccountOrder::LockedFirst] {
+			assert_eq!(shards.list_accounts_by(order), single.list_accounts_by(order).collect::<Vec<_>>());
+		}
+	}
+
+	/// Pseudo-random txs over a few clients and txids, so that disputes,
+	/// txid reuse and cross-client references happen a lot
+	fn random_txs(count: usize, mut seed: u64) -> Vec<Transaction> {
+		let mut next = move |n: u64| {
+			seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+			(seed >> 33) % n
+		};
+		(0..count)
+			.map(|_| {
+				let client = next(7) as ClientId;
+				let txid = next(60) as TxId;
+				let amount = Decimal::new(next(5000) as i64 + 1, 2);
+				match next(10) {
+					0..=3 => Transaction::Deposit { txid, client, amount },
+					4..=5 => Transaction::Withdrawal { txid, client, amount },
+					6..=7 => Transaction::Dispute { client, txid },
+					8 => Transaction::Resolve { client, txid },
+					_ => Transaction::Chargeback { client, txid },
+				}
+			})
+			.collect()
+	}
+
+	#[test]
+	fn same_as_sequential() {
+		for seed in 0..20 {
+			let txs = random_txs(500, seed);
+			for &threads in &[1, 2, 3, 4] {
+				compare(&txs, threads, Store::new);
+			}
+		}
+	}
+
+	#[test]
+	fn same_as_sequential_apply_to_owner() {
+		let make_store = || Store::new().with_ownership_policy(OwnershipPolicy::ApplyToOwner);
+		for seed in 0..20 {
+			let txs = random_txs(500, seed);
+			compare(&txs, 3, make_store);
+		}
+	}
+
+	#[test]
+	fn txid_reused_across_shards() {
+		let amount = Decimal::new(1, 0);
+		let txs = [
+			// client 1 can't withdraw, so txid 1 is free for client 2
+			Transaction::Withdrawal { txid: 1, client: 1, amount },
+			Transaction::Deposit { txid: 1, client: 2, amount },
+			// but now it's taken
+			Transaction::Deposit { txid: 1, client: 1, amount },
+			Transaction::Dispute { client: 2, txid: 1 },
+		];
+		compare(&txs, 2, Store::new);
+
+		let mut sharded = ShardedStore::new(2);
+		for (id, &tx) in txs.iter().enumerate() {
+			sharded.apply(id as u64, tx);
+		}
+		let (mut results, shards) = sharded.finish();
+		results.sort_by_key(|&(id, _)| id);
+		assert_eq!(results[2], (2, Err(Error::DuplicateTx { txid: 1 })));
+		let client2 = shards.list_accounts().into_iter().find(|a| a.client == 2).unwrap();
+		assert_eq!(client2.held, amount);
+	}
+}
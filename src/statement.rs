@@ -0,0 +1,104 @@
+//! Account statements, built from the event log of a `Store`
+
+use rust_decimal::Decimal;
+
+use crate::backend::Backend;
+use crate::store::{Account, AccountSummary, ClientId, Store, TxId, TxType};
+
+/// An accepted operation on an account, with the balance right after it
+#[derive(Debug, PartialEq, Eq)]
+pub struct StatementLine {
+	/// The seq of the event
+	pub seq: u64,
+	pub tx_type: TxType,
+	pub txid: TxId,
+	/// The amount of the tx, or of the disputed tx for disputes,
+	/// resolves and chargebacks
+	pub amount: Decimal,
+	/// The running balance
+	pub balance: AccountSummary,
+}
+
+impl<B: Backend> Store<B> {
+	/// Every accepted operation on a client's account, in order, with the
+	/// running balance. Amounts and balances are rounded like
+	/// `list_accounts`. Empty unless the store was created `with_event_log`.
+	pub fn statement(&self, client: ClientId) -> Vec<StatementLine> {
+		let precision = self.precision();
+		let mut account = Account::new(client);
+		self.events()
+			.iter()
+			.filter(|e| e.client == client)
+			.map(|e| {
+				account.available += e.available;
+				account.held += e.held;
+				account.locked |= e.locked;
+				let amount = match e.tx.amount() {
+					Some(amount) => amount,
+					None => match self.get_transaction(e.tx.txid()) {
+						Some(record) => record.amount,
+						// only when the backend forgot the tx, every dispute
+						// step moves its amount in or out of held
+						None => e.held.abs(),
+					},
+				};
+				StatementLine {
+					seq: e.seq,
+					tx_type: e.tx.tx_type(),
+					txid: e.tx.txid(),
+					amount: precision.apply(amount),
+					balance: account.summary().rounded(precision),
+				}
+			})
+			.collect()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::store::Transaction;
+
+	fn d(s: &str) -> Decimal {
+		s.parse().expect("invalid decimal")
+	}
+
+	#[test]
+	fn running_balance() {
+		let mut store = Store::new().with_event_log();
+		let txs = [
+			Transaction::Deposit { txid: 1, client: 1, amount: d("10") },
+			Transaction::Deposit { txid: 2, client: 2, amount: d("7") },
+			Transaction::Withdrawal { txid: 3, client: 1, amount: d("2.5") },
+			// refused, not on the statement
+			Transaction::Withdrawal { txid: 4, client: 1, amount: d("100") },
+			Transaction::Dispute { client: 1, txid: 3 },
+			Transaction::Chargeback { client: 1, txid: 3 },
+		];
+		for &tx in &txs {
+			let _ = store.apply(tx);
+		}
+
+		let line = |seq, tx_type, txid, amount, available, held, locked| StatementLine {
+			seq,
+			tx_type,
+			txid,
+			amount: d(amount),
+			balance: AccountSummary {
+				client: 1,
+				available: d(available),
+				held: d(held),
+				total: d(available) + d(held),
+				locked,
+			},
+		};
+		assert_eq!(store.statement(1), [
+			line(1, TxType::Deposit, 1, "10", "10", "0", false),
+			line(3, TxType::Withdrawal, 3, "2.5", "7.5", "0", false),
+			line(4, TxType::Dispute, 3, "2.5", "5", "2.5", false),
+			line(5, TxType::Chargeback, 3, "2.5", "5", "0", true),
+		]);
+		assert_eq!(store.statement(1).pop().map(|l| l.balance), store.list_accounts().next());
+		assert_eq!(store.statement(3), []);
+	}
+}
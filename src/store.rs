@@ -2,36 +2,67 @@
 
 use std::collections::HashMap;
 
-use rust_decimal::Decimal;
+use std::cmp::Reverse;
+
+use rust_decimal::{Decimal, RoundingStrategy};
+
+use crate::backend::{Backend, MemoryBackend};
+use crate::journal::{Entry, Journal, LedgerAccount};
 
 pub type TxId = u32;
 pub type ClientId = u16;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum DisputeState {
+	#[default]
 	Normal,
 	Disputed,
 	Resolved,
 	ChargedBack,
 }
 
-impl Default for DisputeState {
-	fn default() -> DisputeState {
-		DisputeState::Normal
+impl DisputeState {
+	pub fn name(&self) -> &'static str {
+		match self {
+			DisputeState::Normal => "normal",
+			DisputeState::Disputed => "disputed",
+			DisputeState::Resolved => "resolved",
+			DisputeState::ChargedBack => "charged_back",
+		}
 	}
 }
 
-#[derive(Debug, PartialEq, Eq)]
+impl std::str::FromStr for DisputeState {
+	type Err = ();
+
+	/// Parse the name returned by `DisputeState::name`
+	fn from_str(name: &str) -> Result<DisputeState, ()> {
+		[DisputeState::Normal, DisputeState::Disputed, DisputeState::Resolved, DisputeState::ChargedBack]
+			.iter().copied().find(|s| s.name() == name).ok_or(())
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Error {
 	/// Tried to perform an action for which the client didn't have enough funds
 	InsufficientFunds {
 		available: Decimal,
 		required: Decimal,
 	},
+	/// Tried to dispute more than the client's available funds and credit limit
+	CreditLimitExceeded {
+		available: Decimal,
+		limit: Decimal,
+		required: Decimal,
+	},
 	/// Got a reference to a tx we don't have
 	TxNotFound {
 		txid: TxId,
 	},
+	/// Got a reference to a tx that fell out of the retention window
+	TxExpired {
+		txid: TxId,
+	},
 	/// Got a tx referencing another tx that's in a state incompatible
 	/// with the new transaction
 	TxInWrongState {
@@ -39,17 +70,147 @@ pub enum Error {
 		action: TxType,
 		state: DisputeState
 	},
+	/// Got a tx referencing a tx that belongs to another client
+	ClientMismatch {
+		txid: TxId,
+		expected: ClientId,
+		got: ClientId,
+	},
+	/// Got a deposit or withdrawal with a zero or negative amount
+	NonPositiveAmount {
+		amount: Decimal,
+	},
+	/// Got a deposit or withdrawal with more than `MAX_DECIMALS` decimal places
+	ExcessivePrecision {
+		amount: Decimal,
+	},
+	/// Tried to release more held funds than the client has, which means
+	/// the held balance got out of sync with the disputed txs
+	HeldUnderflow {
+		held: Decimal,
+		required: Decimal,
+	},
+	/// Applying a tx would take a balance past what a Decimal can hold. The
+	/// fields are the changes the tx would have made.
+	Overflow {
+		client: ClientId,
+		available: Decimal,
+		held: Decimal,
+	},
+	/// Posting a tx to the journal would take a ledger account's balance
+	/// past what a Decimal can hold
+	LedgerOverflow {
+		account: LedgerAccount,
+		amount: Decimal,
+	},
+	/// Got a dispute of a stored tx that is neither a deposit nor a
+	/// withdrawal, which the backend should never hold
+	NotDisputable {
+		txid: TxId,
+		tp: TxType,
+	},
+	/// Got a deposit or withdrawal with a txid that was already used
+	DuplicateTx {
+		txid: TxId,
+	},
+	/// Got a tx for a locked account that the locked policy doesn't allow
+	AccountLocked {
+		client: ClientId,
+		action: TxType,
+	},
+	/// The backend failed to save a change. The store may be left half
+	/// way through the tx and shouldn't be used any further.
+	Storage {
+		message: String,
+	},
+}
+
+impl Error {
+	/// A short machine-readable code for this kind of error
+	pub fn code(&self) -> &'static str {
+		match self {
+			Error::InsufficientFunds { .. } => "insufficient_funds",
+			Error::CreditLimitExceeded { .. } => "credit_limit_exceeded",
+			Error::TxNotFound { .. } => "tx_not_found",
+			Error::TxExpired { .. } => "tx_expired",
+			Error::TxInWrongState { .. } => "tx_in_wrong_state",
+			Error::ClientMismatch { .. } => "client_mismatch",
+			Error::NonPositiveAmount { .. } => "non_positive_amount",
+			Error::ExcessivePrecision { .. } => "excessive_precision",
+			Error::HeldUnderflow { .. } => "held_underflow",
+			Error::Overflow { .. } => "balance_overflow",
+			Error::LedgerOverflow { .. } => "ledger_overflow",
+			Error::NotDisputable { .. } => "not_disputable",
+			Error::DuplicateTx { .. } => "duplicate_tx",
+			Error::AccountLocked { .. } => "account_locked",
+			Error::Storage { .. } => "storage_failed",
+		}
+	}
 }
 
 impl std::fmt::Display for Error {
 	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-		// might write a pretty formatter, using Debug for now
-		std::fmt::Debug::fmt(self, f)
+		match self {
+			Error::InsufficientFunds { available, required } => {
+				write!(f, "insufficient funds: {} required, {} available", required, available)
+			},
+			Error::CreditLimitExceeded { available, limit, required } => write!(
+				f, "credit limit exceeded: {} required, {} available with a credit limit of {}",
+				required, available, limit,
+			),
+			Error::TxNotFound { txid } => write!(f, "tx {} not found", txid),
+			Error::TxExpired { txid } => write!(f, "tx {} is past the retention window", txid),
+			Error::TxInWrongState { txid, action, state } => {
+				write!(f, "can't {} tx {}, it is {}", action.name(), txid, state.name())
+			},
+			Error::ClientMismatch { txid, expected, got } => {
+				write!(f, "tx {} belongs to client {}, not {}", txid, expected, got)
+			},
+			Error::NonPositiveAmount { amount } => write!(f, "amount {} is not positive", amount),
+			Error::ExcessivePrecision { amount } => {
+				write!(f, "amount {} has more than {} decimal places", amount, MAX_DECIMALS)
+			},
+			Error::HeldUnderflow { held, required } => {
+				write!(f, "{} held can't be released, only {} is held", required, held)
+			},
+			Error::Overflow { client, available, held } => write!(
+				f, "changing the balance of client {} by {} available and {} held would overflow",
+				client, available, held,
+			),
+			Error::LedgerOverflow { account, amount } => write!(f, "posting {} to {} would overflow", amount, account),
+			Error::NotDisputable { txid, tp } => write!(f, "tx {} is a {} and can't be disputed", txid, tp.name()),
+			Error::DuplicateTx { txid } => write!(f, "tx {} already exists", txid),
+			Error::AccountLocked { client, action } => {
+				write!(f, "account {} is locked, {} is not allowed", client, action.name())
+			},
+			Error::Storage { message } => write!(f, "failed to save: {}", message),
+		}
 	}
 }
 
 impl std::error::Error for Error {}
 
+impl From<std::io::Error> for Error {
+	fn from(err: std::io::Error) -> Error {
+		Error::Storage { message: err.to_string() }
+	}
+}
+
+/// The maximum number of decimal places accepted in an amount
+pub const MAX_DECIMALS: u32 = 4;
+
+/// Check that an amount is usable for a deposit or withdrawal
+pub fn validate_amount(amount: Decimal) -> Result<(), Error> {
+	if amount.is_sign_negative() || amount.is_zero() {
+		return Err(Error::NonPositiveAmount { amount });
+	}
+	// trailing zeroes don't count, 1.50000 is fine
+	if amount.normalize().scale() > MAX_DECIMALS {
+		return Err(Error::ExcessivePrecision { amount });
+	}
+	Ok(())
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TxType{
 	Deposit,
@@ -59,18 +220,279 @@ pub enum TxType{
 	Chargeback,
 }
 
-#[derive(Debug)]
-struct Tx {
+impl TxType {
+	pub fn name(&self) -> &'static str {
+		match self {
+			TxType::Deposit => "deposit",
+			TxType::Withdrawal => "withdrawal",
+			TxType::Dispute => "dispute",
+			TxType::Resolve => "resolve",
+			TxType::Chargeback => "chargeback",
+		}
+	}
+}
+
+impl std::str::FromStr for TxType {
+	type Err = ();
+
+	/// Parse the name returned by `TxType::name`
+	fn from_str(name: &str) -> Result<TxType, ()> {
+		[TxType::Deposit, TxType::Withdrawal, TxType::Dispute, TxType::Resolve, TxType::Chargeback]
+			.iter().copied().find(|t| t.name() == name).ok_or(())
+	}
+}
+
+/// A transaction as it can be applied to the `Store`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transaction {
+	Deposit {
+		txid: TxId,
+		client: ClientId,
+		amount: Decimal,
+	},
+	Withdrawal {
+		txid: TxId,
+		client: ClientId,
+		amount: Decimal,
+	},
+	/// Dispute the referenced deposit or withdrawal
+	Dispute {
+		client: ClientId,
+		txid: TxId,
+	},
+	/// Resolve the referenced disputed tx
+	Resolve {
+		client: ClientId,
+		txid: TxId,
+	},
+	/// Charge back the referenced disputed tx
+	Chargeback {
+		client: ClientId,
+		txid: TxId,
+	},
+}
+
+impl Transaction {
+	pub fn tx_type(&self) -> TxType {
+		match self {
+			Transaction::Deposit { .. } => TxType::Deposit,
+			Transaction::Withdrawal { .. } => TxType::Withdrawal,
+			Transaction::Dispute { .. } => TxType::Dispute,
+			Transaction::Resolve { .. } => TxType::Resolve,
+			Transaction::Chargeback { .. } => TxType::Chargeback,
+		}
+	}
+
+	pub fn client(&self) -> ClientId {
+		match *self {
+			Transaction::Deposit { client, .. }
+			| Transaction::Withdrawal { client, .. }
+			| Transaction::Dispute { client, .. }
+			| Transaction::Resolve { client, .. }
+			| Transaction::Chargeback { client, .. } => client,
+		}
+	}
+
+	/// The id of the tx itself for deposits and withdrawals,
+	/// or of the referenced tx for the others
+	pub fn txid(&self) -> TxId {
+		match *self {
+			Transaction::Deposit { txid, .. }
+			| Transaction::Withdrawal { txid, .. }
+			| Transaction::Dispute { txid, .. }
+			| Transaction::Resolve { txid, .. }
+			| Transaction::Chargeback { txid, .. } => txid,
+		}
+	}
+
+	/// The amount of a deposit or withdrawal
+	pub fn amount(&self) -> Option<Decimal> {
+		match *self {
+			Transaction::Deposit { amount, .. } | Transaction::Withdrawal { amount, .. } => Some(amount),
+			_ => None,
+		}
+	}
+}
+
+/// What happened to a transaction that was accepted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+	/// The tx was applied
+	Applied,
+	/// The tx was an exact replay of an earlier one and was ignored,
+	/// see `DuplicatePolicy::Idempotent`
+	Replayed,
+}
+
+/// Which transaction types are still accepted on a locked account
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockedPolicy {
+	pub deposit: bool,
+	pub withdrawal: bool,
+	pub dispute: bool,
+	pub resolve: bool,
+	pub chargeback: bool,
+}
+
+impl LockedPolicy {
+	/// Refuse everything on a locked account
+	pub fn frozen() -> LockedPolicy {
+		LockedPolicy {
+			deposit: false,
+			withdrawal: false,
+			dispute: false,
+			resolve: false,
+			chargeback: false,
+		}
+	}
+
+	/// Accept everything on a locked account, locking is informational only
+	pub fn permissive() -> LockedPolicy {
+		LockedPolicy {
+			deposit: true,
+			withdrawal: true,
+			dispute: true,
+			resolve: true,
+			chargeback: true,
+		}
+	}
+
+	pub fn allows(&self, action: TxType) -> bool {
+		match action {
+			TxType::Deposit => self.deposit,
+			TxType::Withdrawal => self.withdrawal,
+			TxType::Dispute => self.dispute,
+			TxType::Resolve => self.resolve,
+			TxType::Chargeback => self.chargeback,
+		}
+	}
+}
+
+impl Default for LockedPolicy {
+	/// No new money movements or disputes, but disputes that were already
+	/// pending when the account got locked can still be settled
+	fn default() -> LockedPolicy {
+		LockedPolicy {
+			deposit: false,
+			withdrawal: false,
+			dispute: false,
+			resolve: true,
+			chargeback: true,
+		}
+	}
+}
+
+/// What to do with a dispute, resolve or chargeback whose client doesn't
+/// match the client of the referenced tx
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OwnershipPolicy {
+	/// Refuse it with `Error::ClientMismatch`
+	#[default]
+	Reject,
+	/// Trust the txid and apply it to the client that owns the referenced tx
+	ApplyToOwner,
+}
+
+/// What to do with a deposit or withdrawal whose txid was already used
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicatePolicy {
+	/// Refuse every reused txid with `Error::DuplicateTx`
+	#[default]
+	Reject,
+	/// Accept an exact replay (same client, type and amount) as a no-op,
+	/// so that at-least-once feeds can be replayed safely. Other reused
+	/// txids are still refused.
+	Idempotent,
+}
+
+/// How disputes on the different tx types are applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DisputePolicy {
+	/// Apply the deposit math from the assignment to every tx: a dispute
+	/// moves the amount from available to held, a resolve moves it back,
+	/// and a chargeback removes it.
+	#[default]
+	AsDeposit,
+	/// Deposits are handled as above. Disputing a withdrawal holds a
+	/// provisional credit for the amount, a resolve discards it, and a
+	/// chargeback returns the funds to available.
+	ByTxType,
+}
+
+/// Whether a dispute may drive the available funds negative. Withdrawals
+/// always need sufficient available funds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NegativeBalancePolicy {
+	/// Refuse disputes for more than the available funds
+	#[default]
+	Reject,
+	/// Always accept disputes, however negative the available funds get
+	AllowNegative,
+	/// Accept disputes as long as the available funds don't go below the
+	/// client's credit limit, see `Store::with_credit_limit`
+	CreditLimit {
+		/// The limit for clients that have none set
+		default: Decimal,
+	},
+}
+
+/// A deposit or withdrawal as kept in the history
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TxRecord {
 	/// The transaction ID
-	txid: TxId,
-	/// The transaction type
-	tp: TxType,
+	pub txid: TxId,
+	/// The transaction type, either a deposit or a withdrawal
+	pub tp: TxType,
 	/// The client this transaction is from
-	client: ClientId,
+	pub client: ClientId,
 	/// The amount of the transaction
-	amount: Decimal,
+	pub amount: Decimal,
 	/// In which state this tx is regarding disputes
-	dispute_state: DisputeState,
+	pub dispute_state: DisputeState,
+}
+
+/// How to round a value that is exactly halfway
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Rounding {
+	/// Round to the nearest even digit, also known as banker's rounding
+	#[default]
+	HalfEven,
+	/// Round away from zero
+	HalfUp,
+}
+
+/// The precision balances are reported with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Precision {
+	/// The number of decimal places, always all of them are shown
+	pub decimals: u32,
+	pub rounding: Rounding,
+}
+
+impl Precision {
+	/// Round and rescale a value to this precision
+	pub fn apply(&self, value: Decimal) -> Decimal {
+		let strategy = match self.rounding {
+			Rounding::HalfEven => RoundingStrategy::MidpointNearestEven,
+			Rounding::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+		};
+		let mut ret = value.round_dp_with_strategy(self.decimals, strategy);
+		ret.rescale(self.decimals);
+		// don't report -0.0000
+		if ret.is_zero() {
+			ret.set_sign_positive(true);
+		}
+		ret
+	}
+}
+
+impl Default for Precision {
+	fn default() -> Precision {
+		Precision {
+			decimals: MAX_DECIMALS,
+			rounding: Rounding::default(),
+		}
+	}
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -87,21 +509,45 @@ pub struct AccountSummary {
 	pub locked: bool,
 }
 
-#[derive(Debug)]
-struct Account {
-	id: ClientId,
+/// The order in which accounts are listed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AccountOrder {
+	/// By client id
+	#[default]
+	Client,
+	/// By total funds, largest first
+	Total,
+	/// Locked accounts first
+	LockedFirst,
+}
+
+impl AccountOrder {
+	/// Sort accounts that are already ordered by client id
+	pub(crate) fn sort(self, accounts: &mut [Account]) {
+		match self {
+			AccountOrder::Client => {},
+			AccountOrder::Total => accounts.sort_by_key(|a| Reverse(a.available + a.held)),
+			AccountOrder::LockedFirst => accounts.sort_by_key(|a| !a.locked),
+		}
+	}
+}
+
+/// A client account as kept in the backend
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Account {
+	pub id: ClientId,
 	/// The total funds that are available for trading, staking, withdrawal, etc
-	available: Decimal,
+	pub available: Decimal,
 	/// The total funds that are held for dispute
-	held: Decimal,
+	pub held: Decimal,
 	/// Whether the account is locked
-	locked: bool,
+	pub locked: bool,
 }
 
 impl Account {
-	fn new(id: ClientId) -> Account {
+	pub fn new(id: ClientId) -> Account {
 		Account {
-			id: id,
+			id,
 			available: Decimal::ZERO,
 			held: Decimal::ZERO,
 			locked: false,
@@ -109,8 +555,30 @@ impl Account {
 	}
 }
 
+impl AccountSummary {
+	/// Whether the available funds went negative, which can only happen
+	/// through disputes allowed by the `NegativeBalancePolicy`
+	pub fn is_negative(&self) -> bool {
+		self.available.is_sign_negative() && !self.available.is_zero()
+	}
+
+	/// Round the balances to the given precision. The total is the sum of
+	/// the rounded balances, so that it always adds up.
+	pub fn rounded(&self, precision: Precision) -> AccountSummary {
+		let available = precision.apply(self.available);
+		let held = precision.apply(self.held);
+		AccountSummary {
+			client: self.client,
+			available,
+			held,
+			total: available + held,
+			locked: self.locked,
+		}
+	}
+}
+
 impl Account {
-	fn summary(&self) -> AccountSummary {
+	pub fn summary(&self) -> AccountSummary {
 		AccountSummary {
 			client: self.id,
 			available: self.available,
@@ -120,6 +588,30 @@ impl Account {
 		}
 	}
 
+	/// Change the balances by the given amounts, or fail without changing
+	/// anything if either of them or their total doesn't fit in a Decimal
+	fn change(&mut self, available: Decimal, held: Decimal) -> Result<(), Error> {
+		let overflow = || Error::Overflow { client: self.id, available, held };
+		let new_available = self.available.checked_add(available).ok_or_else(overflow)?;
+		let new_held = self.held.checked_add(held).ok_or_else(overflow)?;
+		new_available.checked_add(new_held).ok_or_else(overflow)?;
+		self.available = new_available;
+		self.held = new_held;
+		Ok(())
+	}
+
+	/// Utility function to assert that the account has sufficient held balance
+	fn need_held(&self, required_amount: Decimal) -> Result<(), Error> {
+		if self.held >= required_amount {
+			Ok(())
+		} else {
+			Err(Error::HeldUnderflow {
+				held: self.held,
+				required: required_amount,
+			})
+		}
+	}
+
 	/// Utility function to assert that the account has sufficient available balance
 	fn need(&self, required_amount: Decimal) -> Result<(), Error> {
 		if self.available >= required_amount {
@@ -131,55 +623,457 @@ impl Account {
 			})
 		}
 	}
+
+	/// Utility function to assert that the account has sufficient available
+	/// balance, when it is allowed to go negative down to -limit
+	fn need_with_credit(&self, required_amount: Decimal, limit: Decimal) -> Result<(), Error> {
+		// available never goes below -limit, so the sum can only overflow
+		// upwards, where it covers any amount
+		if self.available.checked_add(limit).is_none_or(|a| a >= required_amount) {
+			Ok(())
+		} else {
+			Err(Error::CreditLimitExceeded {
+				available: self.available,
+				limit,
+				required: required_amount,
+			})
+		}
+	}
 }
 
-pub struct Store {
-	accounts: HashMap<ClientId, Account>,
-	history: HashMap<TxId, Tx>,
+/// An accepted operation with the change it made to an account. Replayed
+/// duplicates don't change anything and aren't recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+	/// The position in the log, starting at 1
+	pub seq: u64,
+	pub tx: Transaction,
+	/// The client whose account changed, the owner of the disputed tx
+	pub client: ClientId,
+	/// The change of the available funds
+	pub available: Decimal,
+	/// The change of the held funds
+	pub held: Decimal,
+	/// Whether this locked the account
+	pub locked: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct Store<B = MemoryBackend> {
+	backend: B,
+	locked_policy: LockedPolicy,
+	ownership_policy: OwnershipPolicy,
+	duplicate_policy: DuplicatePolicy,
+	precision: Precision,
+	dispute_policy: DisputePolicy,
+	negative_policy: NegativeBalancePolicy,
+	credit_limits: HashMap<ClientId, Decimal>,
+	events: Option<Vec<Event>>,
+	journal: Option<Journal>,
+	/// The sum of the disputed txs of every client, None once it overflowed
+	disputed: Option<HashMap<ClientId, Option<Decimal>>>,
 }
 
 impl Store {
+	/// Create a store that keeps everything in memory
 	pub fn new() -> Store {
+		Store::default()
+	}
+}
+
+impl<B: Backend> Store<B> {
+	/// Create a store on top of the given backend
+	pub fn with_backend(backend: B) -> Store<B> {
 		Store {
-			accounts: HashMap::new(),
-			history: HashMap::new(),
+			backend,
+			locked_policy: LockedPolicy::default(),
+			ownership_policy: OwnershipPolicy::default(),
+			duplicate_policy: DuplicatePolicy::default(),
+			precision: Precision::default(),
+			dispute_policy: DisputePolicy::default(),
+			negative_policy: NegativeBalancePolicy::default(),
+			credit_limits: HashMap::new(),
+			events: None,
+			journal: None,
+			disputed: None,
 		}
 	}
 
-	fn get_account(&mut self, id: ClientId) -> &mut Account {
-		self.accounts.entry(id).or_insert_with(|| Account::new(id))
+	/// Record every accepted operation as an `Event`. The log grows with
+	/// every tx and is kept in memory only, it isn't part of the snapshots
+	/// of a `PersistentStore`.
+	pub fn with_event_log(mut self) -> Store<B> {
+		self.events = Some(Vec::new());
+		self
+	}
+
+	/// Post every change of the balances to a double-entry `Journal`,
+	/// which `trial_balance` checks against the accounts
+	pub fn with_journal(mut self) -> Store<B> {
+		self.journal = Some(Journal::default());
+		self
 	}
 
+	/// Keep the sum of the disputed txs of every client as they change, so
+	/// that `audit_client` doesn't have to go through the history
+	pub fn with_disputed_sums(mut self) -> Store<B> {
+		self.disputed = Some(HashMap::new());
+		self
+	}
+
+	/// The sums of the disputed txs, if the store keeps them
+	pub(crate) fn disputed_sums(&self) -> Option<&HashMap<ClientId, Option<Decimal>>> {
+		self.disputed.as_ref()
+	}
+
+	/// The journal, if the store keeps one
+	pub fn journal(&self) -> Option<&Journal> {
+		self.journal.as_ref()
+	}
+
+	/// Post an entry to the journal, if there is one
+	pub(crate) fn post(&mut self, from: LedgerAccount, to: LedgerAccount, amount: Decimal) -> Result<(), Error> {
+		match &mut self.journal {
+			Some(journal) => journal.post(Entry { from, to, amount }),
+			None => Ok(()),
+		}
+	}
+
+	/// The backend the store keeps its data in
+	pub fn backend(&self) -> &B {
+		&self.backend
+	}
+
+	/// Set which transaction types are still accepted on locked accounts
+	pub fn with_locked_policy(mut self, policy: LockedPolicy) -> Store<B> {
+		self.locked_policy = policy;
+		self
+	}
+
+	/// Set how references to another client's tx are handled
+	pub fn with_ownership_policy(mut self, policy: OwnershipPolicy) -> Store<B> {
+		self.ownership_policy = policy;
+		self
+	}
+
+	/// Set how reused txids are handled
+	pub fn with_duplicate_policy(mut self, policy: DuplicatePolicy) -> Store<B> {
+		self.duplicate_policy = policy;
+		self
+	}
+
+	/// Check whether a new deposit or withdrawal reuses a txid.
+	/// Returns true if it's an accepted replay that must be ignored.
+	fn check_duplicate(&self, txid: TxId, tp: TxType, client: ClientId, amount: Decimal) -> Result<bool, Error> {
+		let tx = match self.backend.get_tx(txid) {
+			Some(tx) => tx,
+			None => return Ok(false),
+		};
+		let exact = tx.tp == tp && tx.client == client && tx.amount == amount;
+		if exact && self.duplicate_policy == DuplicatePolicy::Idempotent {
+			Ok(true)
+		} else {
+			Err(Error::DuplicateTx { txid })
+		}
+	}
+
+	/// Set the precision account summaries are reported with
+	pub fn with_precision(mut self, precision: Precision) -> Store<B> {
+		self.precision = precision;
+		self
+	}
+
+	/// Set how disputes are applied to the different tx types
+	pub fn with_dispute_policy(mut self, policy: DisputePolicy) -> Store<B> {
+		self.dispute_policy = policy;
+		self
+	}
+
+	/// Set whether disputes may drive the available funds negative
+	pub fn with_negative_policy(mut self, policy: NegativeBalancePolicy) -> Store<B> {
+		self.negative_policy = policy;
+		self
+	}
+
+	/// Set the credit limit of a client, for `NegativeBalancePolicy::CreditLimit`
+	pub fn with_credit_limit(mut self, client: ClientId, limit: Decimal) -> Store<B> {
+		self.credit_limits.insert(client, limit);
+		self
+	}
+
+	/// How far a dispute may drive the client's available funds below zero,
+	/// None if there's no limit
+	fn credit_limit(&self, client: ClientId) -> Option<Decimal> {
+		match self.negative_policy {
+			NegativeBalancePolicy::Reject => Some(Decimal::ZERO),
+			NegativeBalancePolicy::AllowNegative => None,
+			NegativeBalancePolicy::CreditLimit { default } => {
+				Some(self.credit_limits.get(&client).copied().unwrap_or(default))
+			},
+		}
+	}
+
+	/// Whether disputing a tx of the given type holds a provisional credit,
+	/// instead of moving available funds into held
+	fn is_provisional_credit(&self, tp: TxType) -> bool {
+		self.dispute_policy == DisputePolicy::ByTxType && tp == TxType::Withdrawal
+	}
+
+	/// Find the client a tx referencing `txid` should be applied to,
+	/// according to the ownership policy.
+	fn owner_of(&self, client: ClientId, txid: TxId) -> Result<ClientId, Error> {
+		let tx = self.get_tx(txid)?;
+		if tx.client == client {
+			return Ok(client);
+		}
+		match self.ownership_policy {
+			OwnershipPolicy::Reject => Err(Error::ClientMismatch { txid, expected: tx.client, got: client }),
+			OwnershipPolicy::ApplyToOwner => Ok(tx.client),
+		}
+	}
+
+	/// Check that the client's account accepts the given action.
+	/// This must be called before anything is mutated.
+	fn check_locked(&self, client: ClientId, action: TxType) -> Result<(), Error> {
+		let locked = self.backend.get_account(client).is_some_and(|a| a.locked);
+		if locked && !self.locked_policy.allows(action) {
+			return Err(Error::AccountLocked { client, action });
+		}
+		Ok(())
+	}
+
+	/// Get a client's account, creating it if it doesn't exist yet.
+	/// Changes have to be saved with `save_account`.
+	fn get_account(&mut self, id: ClientId) -> Result<Account, Error> {
+		match self.backend.get_account(id) {
+			Some(account) => Ok(account),
+			None => {
+				let account = Account::new(id);
+				self.backend.insert_account(account)?;
+				Ok(account)
+			},
+		}
+	}
+
+	fn save_account(&mut self, account: Account) -> Result<(), Error> {
+		Ok(self.backend.insert_account(account)?)
+	}
+
+	/// List all accounts, ordered by client id
 	pub fn list_accounts(&self) -> impl Iterator<Item = AccountSummary> + '_ {
-		self.accounts.values().map(|a| a.summary())
+		self.backend.accounts().map(move |a| a.summary().rounded(self.precision))
+	}
+
+	/// List the accounts with negative available funds, ordered by client
+	/// id. Also those that round to zero at the precision.
+	pub fn negative_accounts(&self) -> impl Iterator<Item = AccountSummary> + '_ {
+		self.backend.accounts()
+			.map(|a| a.summary())
+			.filter(|a| a.is_negative())
+			.map(move |a| a.rounded(self.precision))
+	}
+
+	/// List all accounts in the given order. Ties are ordered by client id.
+	/// The backend keeps them by client, so that order goes straight through
+	/// while the others copy all accounts to sort them.
+	pub fn list_accounts_by(&self, order: AccountOrder) -> Box<dyn Iterator<Item = AccountSummary> + '_> {
+		let round = move |a: Account| a.summary().rounded(self.precision);
+		if order == AccountOrder::Client {
+			return Box::new(self.backend.accounts().map(round));
+		}
+		let mut accounts = self.backend.accounts().collect::<Vec<_>>();
+		order.sort(&mut accounts);
+		Box::new(accounts.into_iter().map(round))
 	}
 
-	fn get_tx(&mut self, txid: TxId) -> Result<&mut Tx, Error> {
-		self.history.get_mut(&txid).ok_or_else(|| Error::TxNotFound {
-			txid: txid,
+	/// Look up a deposit or withdrawal
+	pub fn get_transaction(&self, txid: TxId) -> Option<TxRecord> {
+		self.backend.get_tx(txid)
+	}
+
+	/// All deposits and withdrawals, in no particular order
+	pub fn transactions(&self) -> impl Iterator<Item = TxRecord> + '_ {
+		self.backend.txs()
+	}
+
+	pub(crate) fn precision(&self) -> Precision {
+		self.precision
+	}
+
+	/// All accounts with their exact balances, not rounded to the precision
+	pub(crate) fn exact_accounts(&self) -> impl Iterator<Item = AccountSummary> + '_ {
+		self.backend.accounts().map(|a| a.summary())
+	}
+
+	/// Put back an account as it was saved, overwriting any existing one
+	pub(crate) fn restore_account(&mut self, summary: &AccountSummary) -> Result<(), Error> {
+		let client = summary.client;
+		let old = self.backend.get_account(client).unwrap_or_else(|| Account::new(client));
+		let available = summary.available.checked_sub(old.available);
+		let held = summary.held.checked_sub(old.held);
+		let (available, held) = available.zip(held).ok_or(Error::Overflow {
+			client,
+			available: summary.available,
+			held: summary.held,
+		})?;
+		// the journal starts from the restored balances
+		self.post(LedgerAccount::Bank, LedgerAccount::Available(client), available)?;
+		self.post(LedgerAccount::Bank, LedgerAccount::Held(client), held)?;
+		self.save_account(Account {
+			id: client,
+			available: summary.available,
+			held: summary.held,
+			locked: summary.locked,
+		})
+	}
+
+	/// Put back a tx as it was saved, overwriting any existing one
+	pub(crate) fn restore_transaction(&mut self, record: &TxRecord) -> Result<(), Error> {
+		self.save_tx(*record)
+	}
+
+	/// Save a tx, and follow its dispute state in the disputed sums if the
+	/// store keeps them
+	fn save_tx(&mut self, tx: TxRecord) -> Result<(), Error> {
+		let old = match self.disputed {
+			Some(_) => self.backend.get_tx(tx.txid),
+			None => None,
+		};
+		self.backend.insert_tx(tx)?;
+
+		if let Some(sums) = &mut self.disputed {
+			if let Some(old) = old.filter(|old| old.dispute_state == DisputeState::Disputed) {
+				let sum = sums.entry(old.client).or_insert(Some(Decimal::ZERO));
+				*sum = sum.and_then(|sum| sum.checked_sub(old.amount));
+			}
+			if tx.dispute_state == DisputeState::Disputed {
+				let sum = sums.entry(tx.client).or_insert(Some(Decimal::ZERO));
+				*sum = sum.and_then(|sum| sum.checked_add(tx.amount));
+			}
+		}
+		Ok(())
+	}
+
+	fn get_tx(&self, txid: TxId) -> Result<TxRecord, Error> {
+		self.backend.get_tx(txid).ok_or_else(|| if self.backend.is_expired(txid) {
+			Error::TxExpired { txid }
+		} else {
+			Error::TxNotFound { txid }
 		})
 	}
 
+	/// Apply any kind of transaction, and record it in the event log if
+	/// that's on and the tx changed anything
+	pub fn apply(&mut self, tx: Transaction) -> Result<Outcome, Error> {
+		if self.events.is_none() {
+			return self.apply_tx(tx);
+		}
+
+		let client = self.affected_client(tx);
+		let before = self.backend.get_account(client).unwrap_or_else(|| Account::new(client));
+		let outcome = self.apply_tx(tx)?;
+		if outcome == Outcome::Applied {
+			let after = self.backend.get_account(client).unwrap_or(before);
+			if let Some(events) = &mut self.events {
+				events.push(Event {
+					seq: events.len() as u64 + 1,
+					tx,
+					client,
+					available: after.available - before.available,
+					held: after.held - before.held,
+					locked: after.locked && !before.locked,
+				});
+			}
+		}
+		Ok(outcome)
+	}
+
+	fn apply_tx(&mut self, tx: Transaction) -> Result<Outcome, Error> {
+		match tx {
+			Transaction::Deposit { txid, client, amount } => self.deposit(txid, client, amount),
+			Transaction::Withdrawal { txid, client, amount } => self.withdrawal(txid, client, amount),
+			Transaction::Dispute { client, txid } => self.dispute(client, txid),
+			Transaction::Resolve { client, txid } => self.resolve(client, txid),
+			Transaction::Chargeback { client, txid } => self.chargeback(client, txid),
+		}
+	}
+
+	/// The client whose account a tx changes if it's accepted. For
+	/// disputes that's the owner of the referenced tx, which only differs
+	/// from the tx's client with `OwnershipPolicy::ApplyToOwner`.
+	fn affected_client(&self, tx: Transaction) -> ClientId {
+		match tx {
+			Transaction::Deposit { client, .. } | Transaction::Withdrawal { client, .. } => client,
+			Transaction::Dispute { client, txid }
+			| Transaction::Resolve { client, txid }
+			| Transaction::Chargeback { client, txid } => {
+				self.backend.get_tx(txid).map_or(client, |tx| tx.client)
+			},
+		}
+	}
+
+	/// The accepted operations so far, oldest first. Empty unless the
+	/// store was created `with_event_log`.
+	pub fn events(&self) -> &[Event] {
+		self.events.as_deref().unwrap_or(&[])
+	}
+
+	/// A client's balance right after the event `seq`, rounded like
+	/// `list_accounts`. `None` if the account had no event up to then.
+	pub fn balance_at(&self, client: ClientId, seq: u64) -> Option<AccountSummary> {
+		let mut account = None;
+		for event in self.events().iter().take_while(|e| e.seq <= seq).filter(|e| e.client == client) {
+			let account = account.get_or_insert_with(|| Account::new(client));
+			account.available += event.available;
+			account.held += event.held;
+			account.locked |= event.locked;
+		}
+		account.map(|a: Account| a.summary().rounded(self.precision))
+	}
+
+	/// Rebuild the state from events recorded by another store, by applying
+	/// their txs in order. This store should be new and set up with the
+	/// same policies. Fails on the first tx that isn't accepted again.
+	pub fn replay(&mut self, events: impl IntoIterator<Item = Event>) -> Result<(), Error> {
+		for event in events {
+			self.apply(event.tx)?;
+		}
+		Ok(())
+	}
+
 	pub fn handle_deposit(
 		&mut self,
 		txid: TxId,
 		client: ClientId,
 		amount: Decimal,
 	) -> Result<(), Error> {
-		assert!(amount.is_sign_positive());
-		{
-			let account = self.get_account(client);
-			account.available += amount;
+		self.apply(Transaction::Deposit { txid, client, amount }).map(|_| ())
+	}
+
+	fn deposit(
+		&mut self,
+		txid: TxId,
+		client: ClientId,
+		amount: Decimal,
+	) -> Result<Outcome, Error> {
+		validate_amount(amount)?;
+		if self.check_duplicate(txid, TxType::Deposit, client, amount)? {
+			return Ok(Outcome::Replayed);
 		}
+		self.check_locked(client, TxType::Deposit)?;
+		let mut account = self.get_account(client)?;
+		account.change(amount, Decimal::ZERO)?;
+		self.post(LedgerAccount::Bank, LedgerAccount::Available(client), amount)?;
+		self.save_account(account)?;
 
-		self.history.insert(txid, Tx {
-			txid: txid,
+		self.save_tx(TxRecord {
+			txid,
 			tp: TxType::Deposit,
-			client: client,
-			amount: amount,
+			client,
+			amount,
 			dispute_state: DisputeState::Normal,
-		});
-		Ok(())
+		})?;
+		Ok(Outcome::Applied)
 	}
 
 	pub fn handle_withdrawal(
@@ -188,21 +1082,34 @@ impl Store {
 		client: ClientId,
 		amount: Decimal,
 	) -> Result<(), Error> {
-		assert!(amount.is_sign_positive());
-		{
-			let account = self.get_account(client);
-			account.need(amount)?;
-			account.available -= amount;
+		self.apply(Transaction::Withdrawal { txid, client, amount }).map(|_| ())
+	}
+
+	fn withdrawal(
+		&mut self,
+		txid: TxId,
+		client: ClientId,
+		amount: Decimal,
+	) -> Result<Outcome, Error> {
+		validate_amount(amount)?;
+		if self.check_duplicate(txid, TxType::Withdrawal, client, amount)? {
+			return Ok(Outcome::Replayed);
 		}
+		self.check_locked(client, TxType::Withdrawal)?;
+		let mut account = self.get_account(client)?;
+		account.need(amount)?;
+		account.change(-amount, Decimal::ZERO)?;
+		self.post(LedgerAccount::Available(client), LedgerAccount::Bank, amount)?;
+		self.save_account(account)?;
 
-		self.history.insert(txid, Tx {
-			txid: txid,
+		self.save_tx(TxRecord {
+			txid,
 			tp: TxType::Withdrawal,
-			client: client,
-			amount: amount,
+			client,
+			amount,
 			dispute_state: DisputeState::Normal,
-		});
-		Ok(())
+		})?;
+		Ok(Outcome::Applied)
 	}
 
 	pub fn handle_dispute(
@@ -210,33 +1117,53 @@ impl Store {
 		client: ClientId,
 		txid: TxId,
 	) -> Result<(), Error> {
-		let amount = {
-			let tx = self.get_tx(txid)?;
-			if tx.dispute_state != DisputeState::Normal {
-				return Err(Error::TxInWrongState { txid, action: TxType::Dispute, state: tx.dispute_state });
-			}
+		self.apply(Transaction::Dispute { client, txid }).map(|_| ())
+	}
 
-			// NB there's something strange in the assignment..
-			// dispute only make sense on withdrawals, not really on deposits
-			// but the math described in the assignment only makes sense for
-			// deposits. this is an implementation according to the assignment,
-			// but in practice a different dispute resolution should be applied
-			// for different types of disputed transactions
+	fn dispute(
+		&mut self,
+		client: ClientId,
+		txid: TxId,
+	) -> Result<Outcome, Error> {
+		let client = self.owner_of(client, txid)?;
+		self.check_locked(client, TxType::Dispute)?;
+		let tx = self.get_tx(txid)?;
+		if tx.dispute_state != DisputeState::Normal {
+			return Err(Error::TxInWrongState { txid, action: TxType::Dispute, state: tx.dispute_state });
+		}
 
-			// since only withdrawals and deposits are logged in the history, assert this
-			assert!(tx.tp == TxType::Withdrawal || tx.tp == TxType::Deposit,
-				"impossible tx type disputed: {:?}", tx.tp,
-			);
+		// NB there's something strange in the assignment..
+		// dispute only make sense on withdrawals, not really on deposits
+		// but the math described in the assignment only makes sense for
+		// deposits. DisputePolicy selects whether the assignment's math is
+		// applied to all txs or withdrawals get their own resolution.
 
-			tx.dispute_state = DisputeState::Disputed;
-			tx.amount
-		};
-		
-		let account = self.get_account(client);
-		account.need(amount)?;
-		account.available -= amount;
-		account.held += amount;
-		Ok(())
+		// only withdrawals and deposits are logged in the history
+		if tx.tp != TxType::Withdrawal && tx.tp != TxType::Deposit {
+			return Err(Error::NotDisputable { txid, tp: tx.tp });
+		}
+
+		let amount = tx.amount;
+		let provisional = self.is_provisional_credit(tx.tp);
+		let credit_limit = self.credit_limit(client);
+		let mut account = self.get_account(client)?;
+		if provisional {
+			// the money already left the account, hold it as a credit
+			// until we know whether it comes back
+			account.change(Decimal::ZERO, amount)?;
+		} else {
+			match credit_limit {
+				Some(limit) if limit.is_zero() => account.need(amount)?,
+				Some(limit) => account.need_with_credit(amount, limit)?,
+				None => {},
+			}
+			account.change(-amount, amount)?;
+		}
+		let from = if provisional { LedgerAccount::Chargebacks } else { LedgerAccount::Available(client) };
+		self.post(from, LedgerAccount::Held(client), amount)?;
+		self.save_account(account)?;
+		self.save_tx(TxRecord { dispute_state: DisputeState::Disputed, ..tx })?;
+		Ok(Outcome::Applied)
 	}
 
 	pub fn handle_resolve(
@@ -244,22 +1171,35 @@ impl Store {
 		client: ClientId,
 		txid: TxId,
 	) -> Result<(), Error> {
-		let amount = {
-			let tx = self.get_tx(txid)?;
-			if tx.dispute_state != DisputeState::Disputed {
-				return Err(Error::TxInWrongState { txid, action: TxType::Resolve, state: tx.dispute_state });
-			}
-			tx.dispute_state = DisputeState::Resolved;
+		self.apply(Transaction::Resolve { client, txid }).map(|_| ())
+	}
 
-			// the tx type was already checked by the dispute tx
-			tx.amount
-		};
-		
-		let account = self.get_account(client);
-		assert!(account.held >= amount);
-		account.available += amount;
-		account.held -= amount;
-		Ok(())
+	fn resolve(
+		&mut self,
+		client: ClientId,
+		txid: TxId,
+	) -> Result<Outcome, Error> {
+		let client = self.owner_of(client, txid)?;
+		self.check_locked(client, TxType::Resolve)?;
+		let tx = self.get_tx(txid)?;
+		if tx.dispute_state != DisputeState::Disputed {
+			return Err(Error::TxInWrongState { txid, action: TxType::Resolve, state: tx.dispute_state });
+		}
+
+		// the tx type was already checked by the dispute tx
+		let amount = tx.amount;
+		let provisional = self.is_provisional_credit(tx.tp);
+
+		let mut account = self.get_account(client)?;
+		account.need_held(amount)?;
+		// a provisional credit is simply dropped
+		let available = if provisional { Decimal::ZERO } else { amount };
+		account.change(available, -amount)?;
+		let to = if provisional { LedgerAccount::Chargebacks } else { LedgerAccount::Available(client) };
+		self.post(LedgerAccount::Held(client), to, amount)?;
+		self.save_account(account)?;
+		self.save_tx(TxRecord { dispute_state: DisputeState::Resolved, ..tx })?;
+		Ok(Outcome::Applied)
 	}
 
 	pub fn handle_chargeback(
@@ -267,28 +1207,43 @@ impl Store {
 		client: ClientId,
 		txid: TxId,
 	) -> Result<(), Error> {
-		let amount = {
-			let tx = self.get_tx(txid)?;
-			if tx.dispute_state != DisputeState::Disputed {
-				return Err(Error::TxInWrongState { txid, action: TxType::Chargeback, state: tx.dispute_state });
-			}
-			tx.dispute_state = DisputeState::ChargedBack;
+		self.apply(Transaction::Chargeback { client, txid }).map(|_| ())
+	}
 
-			// the tx type was already checked by the dispute tx
-			tx.amount
-		};
-		
-		let account = self.get_account(client);
-		assert!(account.held >= amount);
-		account.held -= amount;
+	fn chargeback(
+		&mut self,
+		client: ClientId,
+		txid: TxId,
+	) -> Result<Outcome, Error> {
+		let client = self.owner_of(client, txid)?;
+		self.check_locked(client, TxType::Chargeback)?;
+		let tx = self.get_tx(txid)?;
+		if tx.dispute_state != DisputeState::Disputed {
+			return Err(Error::TxInWrongState { txid, action: TxType::Chargeback, state: tx.dispute_state });
+		}
+
+		// the tx type was already checked by the dispute tx
+		let amount = tx.amount;
+		let provisional = self.is_provisional_credit(tx.tp);
+
+		let mut account = self.get_account(client)?;
+		account.need_held(amount)?;
+		// a provisional credit is returned to the client
+		let available = if provisional { amount } else { Decimal::ZERO };
+		account.change(available, -amount)?;
 		account.locked = true;
-		Ok(())
+		let to = if provisional { LedgerAccount::Available(client) } else { LedgerAccount::Chargebacks };
+		self.post(LedgerAccount::Held(client), to, amount)?;
+		self.save_account(account)?;
+		self.save_tx(TxRecord { dispute_state: DisputeState::ChargedBack, ..tx })?;
+		Ok(Outcome::Applied)
 	}
 }
 
 #[cfg(test)]
 mod test {
 	use super::*;
+	use crate::backend::{AccountRepository, TxRepository};
 	use rust_decimal::Decimal;
 
 	/// Helper to create a decimal.
@@ -306,38 +1261,38 @@ mod test {
 
 		// do a deposit
 		txid += 1;
-		store.handle_deposit(txid, ACC, d("5.12345")).unwrap();
-		assert_eq!(store.get_account(ACC).summary(), AccountSummary {
+		store.handle_deposit(txid, ACC, d("5.1234")).unwrap();
+		assert_eq!(store.get_account(ACC).unwrap().summary(), AccountSummary {
 			client: ACC,
-			available: d("5.12345"),
+			available: d("5.1234"),
 			held: d("0"),
-			total: d("5.12345"),
+			total: d("5.1234"),
 			locked: false,
 		});
 
 		// withdraw too much
-		let ret = store.handle_withdrawal(txid, ACC, d("6")).unwrap_err();
-		assert_eq!(ret, Error::InsufficientFunds { available: d("5.12345"), required: d("6") });
+		let ret = store.handle_withdrawal(txid + 1, ACC, d("6")).unwrap_err();
+		assert_eq!(ret, Error::InsufficientFunds { available: d("5.1234"), required: d("6") });
 
 		// do a withdrawal
 		txid += 1;
 		store.handle_withdrawal(txid, ACC, d("4.01")).unwrap();
-		assert_eq!(store.get_account(ACC).summary(), AccountSummary {
+		assert_eq!(store.get_account(ACC).unwrap().summary(), AccountSummary {
 			client: ACC,
-			available: d("1.11345"),
+			available: d("1.1134"),
 			held: d("0"),
-			total: d("1.11345"),
+			total: d("1.1134"),
 			locked: false,
 		});
 
 		// do another deposit
 		txid += 1;
 		store.handle_deposit(txid, ACC, d("3")).unwrap();
-		assert_eq!(store.get_account(ACC).summary(), AccountSummary {
+		assert_eq!(store.get_account(ACC).unwrap().summary(), AccountSummary {
 			client: ACC,
-			available: d("4.11345"),
+			available: d("4.1134"),
 			held: d("0"),
-			total: d("4.11345"),
+			total: d("4.1134"),
 			locked: false,
 		});
 		let deposit_txid = txid;
@@ -347,11 +1302,11 @@ mod test {
 
 		// dispute it
 		store.handle_dispute(ACC, deposit_txid).unwrap();
-		assert_eq!(store.get_account(ACC).summary(), AccountSummary {
+		assert_eq!(store.get_account(ACC).unwrap().summary(), AccountSummary {
 			client: ACC,
-			available: d("1.11345"),
+			available: d("1.1134"),
 			held: d("3"),
-			total: d("4.11345"),
+			total: d("4.1134"),
 			locked: false,
 		});
 
@@ -365,11 +1320,11 @@ mod test {
 
 		// resolve it
 		store.handle_resolve(ACC, deposit_txid).unwrap();
-		assert_eq!(store.get_account(ACC).summary(), AccountSummary {
+		assert_eq!(store.get_account(ACC).unwrap().summary(), AccountSummary {
 			client: ACC,
-			available: d("4.11345"),
+			available: d("4.1134"),
 			held: d("0"),
-			total: d("4.11345"),
+			total: d("4.1134"),
 			locked: false,
 		});
 
@@ -399,32 +1354,32 @@ mod test {
 		// do another deposit
 		txid += 1;
 		store.handle_deposit(txid, ACC, d("9")).unwrap();
-		assert_eq!(store.get_account(ACC).summary(), AccountSummary {
+		assert_eq!(store.get_account(ACC).unwrap().summary(), AccountSummary {
 			client: ACC,
-			available: d("13.11345"),
+			available: d("13.1134"),
 			held: d("0"),
-			total: d("13.11345"),
+			total: d("13.1134"),
 			locked: false,
 		});
 		let deposit_txid = txid;
 
 		// dispute it
 		store.handle_dispute(ACC, deposit_txid).unwrap();
-		assert_eq!(store.get_account(ACC).summary(), AccountSummary {
+		assert_eq!(store.get_account(ACC).unwrap().summary(), AccountSummary {
 			client: ACC,
-			available: d("4.11345"),
+			available: d("4.1134"),
 			held: d("9"),
-			total: d("13.11345"),
+			total: d("13.1134"),
 			locked: false,
 		});
 
 		// charge it back
 		store.handle_chargeback(ACC, deposit_txid).unwrap();
-		assert_eq!(store.get_account(ACC).summary(), AccountSummary {
+		assert_eq!(store.get_account(ACC).unwrap().summary(), AccountSummary {
 			client: ACC,
-			available: d("4.11345"),
+			available: d("4.1134"),
 			held: d("0"),
-			total: d("4.11345"),
+			total: d("4.1134"),
 			locked: true,
 		});
 
@@ -436,4 +1391,566 @@ mod test {
 			state: DisputeState::ChargedBack,
 		});
 	}
+
+	/// Builds a store with a locked account ACC, which has a disputed
+	/// deposit 3 and an undisputed deposit 4 left over.
+	fn locked_store(policy: LockedPolicy) -> Store {
+		const ACC: u16 = 1;
+		let mut store = Store::new().with_locked_policy(policy);
+		store.handle_deposit(1, ACC, d("10")).unwrap();
+		store.handle_deposit(2, ACC, d("5")).unwrap();
+		store.handle_deposit(3, ACC, d("2")).unwrap();
+		store.handle_deposit(4, ACC, d("1")).unwrap();
+		store.handle_dispute(ACC, 2).unwrap();
+		store.handle_dispute(ACC, 3).unwrap();
+		store.handle_chargeback(ACC, 2).unwrap();
+		assert!(store.get_account(ACC).unwrap().locked);
+		store
+	}
+
+	/// Apply every tx type to a locked account and check it is accepted
+	/// only if the policy allows it.
+	fn check_locked_policy(policy: LockedPolicy) {
+		const ACC: u16 = 1;
+		type Attempt = fn(&mut Store) -> Result<(), Error>;
+		let attempts: [(TxType, Attempt); 5] = [
+			(TxType::Deposit, |s| s.handle_deposit(10, ACC, d("1"))),
+			(TxType::Withdrawal, |s| s.handle_withdrawal(10, ACC, d("1"))),
+			(TxType::Dispute, |s| s.handle_dispute(ACC, 4)),
+			(TxType::Resolve, |s| s.handle_resolve(ACC, 3)),
+			(TxType::Chargeback, |s| s.handle_chargeback(ACC, 3)),
+		];
+		for (action, attempt) in attempts.iter() {
+			let mut store = locked_store(policy);
+			let before = store.get_account(ACC).unwrap().summary();
+			let ret = attempt(&mut store);
+			if policy.allows(*action) {
+				assert_eq!(ret, Ok(()), "{:?} should be allowed by {:?}", action, policy);
+				assert_ne!(store.get_account(ACC).unwrap().summary(), before);
+			} else {
+				assert_eq!(ret, Err(Error::AccountLocked { client: ACC, action: *action }));
+				assert_eq!(store.get_account(ACC).unwrap().summary(), before);
+				// the referenced txs must not have changed state either
+				assert_eq!(store.get_transaction(3).unwrap().dispute_state, DisputeState::Disputed);
+				assert_eq!(store.get_transaction(4).unwrap().dispute_state, DisputeState::Normal);
+				assert_eq!(store.get_transaction(10), None);
+			}
+		}
+	}
+
+	#[test]
+	fn locked_default_policy() {
+		let policy = LockedPolicy::default();
+		assert!(!policy.allows(TxType::Deposit));
+		assert!(!policy.allows(TxType::Withdrawal));
+		assert!(!policy.allows(TxType::Dispute));
+		assert!(policy.allows(TxType::Resolve));
+		assert!(policy.allows(TxType::Chargeback));
+		check_locked_policy(policy);
+	}
+
+	#[test]
+	fn locked_frozen_and_permissive() {
+		check_locked_policy(LockedPolicy::frozen());
+		check_locked_policy(LockedPolicy::permissive());
+	}
+
+	#[test]
+	fn locked_policy_combinations() {
+		for bits in 0..32u8 {
+			check_locked_policy(LockedPolicy {
+				deposit: bits & 1 != 0,
+				withdrawal: bits & 2 != 0,
+				dispute: bits & 4 != 0,
+				resolve: bits & 8 != 0,
+				chargeback: bits & 16 != 0,
+			});
+		}
+	}
+
+	#[test]
+	fn unlocked_accounts_ignore_policy() {
+		let mut store = Store::new().with_locked_policy(LockedPolicy::frozen());
+		store.handle_deposit(1, 1, d("3")).unwrap();
+		store.handle_withdrawal(2, 1, d("1")).unwrap();
+		store.handle_dispute(1, 2).unwrap();
+		store.handle_resolve(1, 2).unwrap();
+		// other clients are unaffected by a locked account
+		store.handle_deposit(3, 2, d("3")).unwrap();
+		store.handle_dispute(2, 3).unwrap();
+		store.handle_chargeback(2, 3).unwrap();
+		assert!(store.get_account(2).unwrap().locked);
+		store.handle_deposit(4, 1, d("1")).unwrap();
+		assert_eq!(store.handle_deposit(5, 2, d("1")), Err(Error::AccountLocked { client: 2, action: TxType::Deposit }));
+	}
+
+	#[test]
+	fn ownership_reject() {
+		let mut store = Store::new();
+		store.handle_deposit(1, 1, d("5")).unwrap();
+		store.handle_deposit(2, 2, d("5")).unwrap();
+
+		let mismatch = Error::ClientMismatch { txid: 1, expected: 1, got: 2 };
+		assert_eq!(store.handle_dispute(2, 1), Err(mismatch.clone()));
+		store.handle_dispute(1, 1).unwrap();
+		assert_eq!(store.handle_resolve(2, 1), Err(mismatch.clone()));
+		assert_eq!(store.handle_chargeback(2, 1), Err(mismatch));
+
+		// nothing moved on either account
+		assert_eq!(store.get_account(1).unwrap().summary().held, d("5"));
+		assert_eq!(store.get_account(2).unwrap().summary(), AccountSummary {
+			client: 2,
+			available: d("5"),
+			held: d("0"),
+			total: d("5"),
+			locked: false,
+		});
+	}
+
+	#[test]
+	fn ownership_apply_to_owner() {
+		let mut store = Store::new().with_ownership_policy(OwnershipPolicy::ApplyToOwner);
+		store.handle_deposit(1, 1, d("5")).unwrap();
+		store.handle_deposit(2, 2, d("5")).unwrap();
+
+		store.handle_dispute(2, 1).unwrap();
+		assert_eq!(store.get_account(1).unwrap().summary().held, d("5"));
+		store.handle_chargeback(2, 1).unwrap();
+		assert_eq!(store.get_account(1).unwrap().summary(), AccountSummary {
+			client: 1,
+			available: d("0"),
+			held: d("0"),
+			total: d("0"),
+			locked: true,
+		});
+		assert_eq!(store.get_account(2).unwrap().summary(), AccountSummary {
+			client: 2,
+			available: d("5"),
+			held: d("0"),
+			total: d("5"),
+			locked: false,
+		});
+	}
+
+	#[test]
+	fn duplicate_reject() {
+		let mut store = Store::new();
+		store.handle_deposit(1, 1, d("5")).unwrap();
+		store.handle_dispute(1, 1).unwrap();
+
+		// an exact replay and a conflicting reuse are both refused
+		assert_eq!(store.handle_deposit(1, 1, d("5")), Err(Error::DuplicateTx { txid: 1 }));
+		assert_eq!(store.handle_withdrawal(1, 2, d("1")), Err(Error::DuplicateTx { txid: 1 }));
+
+		// the original tx and its dispute are untouched
+		assert_eq!(store.get_account(1).unwrap().summary(), AccountSummary {
+			client: 1,
+			available: d("0"),
+			held: d("5"),
+			total: d("5"),
+			locked: false,
+		});
+		assert_eq!(store.get_transaction(1).unwrap().dispute_state, DisputeState::Disputed);
+		assert_eq!(store.backend.get_account(2), None);
+	}
+
+	#[test]
+	fn duplicate_idempotent() {
+		let mut store = Store::new().with_duplicate_policy(DuplicatePolicy::Idempotent);
+		store.handle_deposit(1, 1, d("5")).unwrap();
+		store.handle_withdrawal(2, 1, d("2")).unwrap();
+
+		// exact replays are no-ops
+		store.handle_deposit(1, 1, d("5")).unwrap();
+		store.handle_withdrawal(2, 1, d("2")).unwrap();
+		assert_eq!(store.get_account(1).unwrap().summary().available, d("3"));
+
+		// anything else reusing the txid is refused
+		assert_eq!(store.handle_deposit(1, 1, d("6")), Err(Error::DuplicateTx { txid: 1 }));
+		assert_eq!(store.handle_deposit(1, 2, d("5")), Err(Error::DuplicateTx { txid: 1 }));
+		assert_eq!(store.handle_withdrawal(1, 1, d("5")), Err(Error::DuplicateTx { txid: 1 }));
+		assert_eq!(store.get_account(1).unwrap().summary().available, d("3"));
+	}
+
+	#[test]
+	fn amount_validation() {
+		assert_eq!(validate_amount(d("1")), Ok(()));
+		assert_eq!(validate_amount(d("0.0001")), Ok(()));
+		assert_eq!(validate_amount(d("1.50000")), Ok(()));
+		assert_eq!(validate_amount(d("0")), Err(Error::NonPositiveAmount { amount: d("0") }));
+		assert_eq!(validate_amount(d("-0.00")), Err(Error::NonPositiveAmount { amount: d("-0.00") }));
+		assert_eq!(validate_amount(d("-1")), Err(Error::NonPositiveAmount { amount: d("-1") }));
+		assert_eq!(validate_amount(d("0.00001")), Err(Error::ExcessivePrecision { amount: d("0.00001") }));
+
+		// invalid amounts are refused before anything is recorded
+		let mut store = Store::new();
+		assert_eq!(store.handle_deposit(1, 1, d("-5")), Err(Error::NonPositiveAmount { amount: d("-5") }));
+		assert_eq!(store.handle_deposit(1, 1, d("0")), Err(Error::NonPositiveAmount { amount: d("0") }));
+		assert_eq!(store.handle_withdrawal(1, 1, d("1.23456")), Err(Error::ExcessivePrecision { amount: d("1.23456") }));
+		assert_eq!(store.list_accounts().count(), 0);
+		assert_eq!(store.transactions().count(), 0);
+	}
+
+	#[test]
+	fn failed_dispute_leaves_tx_alone() {
+		let mut store = Store::new();
+		store.handle_deposit(1, 1, d("5")).unwrap();
+		store.handle_withdrawal(2, 1, d("4")).unwrap();
+		let ret = store.handle_dispute(1, 1).unwrap_err();
+		assert_eq!(ret, Error::InsufficientFunds { available: d("1"), required: d("5") });
+		assert_eq!(store.get_transaction(1).unwrap().dispute_state, DisputeState::Normal);
+	}
+
+	#[test]
+	fn held_underflow() {
+		let mut store = Store::new();
+		store.handle_deposit(1, 1, d("5")).unwrap();
+		store.handle_dispute(1, 1).unwrap();
+
+		// corrupt the held balance, this used to panic
+		let mut account = store.get_account(1).unwrap();
+		account.held = d("2");
+		store.save_account(account).unwrap();
+		let underflow = Error::HeldUnderflow { held: d("2"), required: d("5") };
+		assert_eq!(store.handle_resolve(1, 1), Err(underflow.clone()));
+		assert_eq!(store.handle_chargeback(1, 1), Err(underflow));
+		assert_eq!(store.get_transaction(1).unwrap().dispute_state, DisputeState::Disputed);
+		assert!(!store.get_account(1).unwrap().locked);
+	}
+
+	#[test]
+	fn apply() {
+		let mut store = Store::new().with_duplicate_policy(DuplicatePolicy::Idempotent);
+		let deposit = Transaction::Deposit { txid: 1, client: 1, amount: d("5") };
+		assert_eq!(store.apply(deposit), Ok(Outcome::Applied));
+		assert_eq!(store.apply(deposit), Ok(Outcome::Replayed));
+		assert_eq!(store.apply(Transaction::Withdrawal { txid: 2, client: 1, amount: d("1") }), Ok(Outcome::Applied));
+		assert_eq!(store.apply(Transaction::Dispute { client: 1, txid: 2 }), Ok(Outcome::Applied));
+		assert_eq!(store.apply(Transaction::Resolve { client: 1, txid: 2 }), Ok(Outcome::Applied));
+		assert_eq!(store.apply(Transaction::Chargeback { client: 1, txid: 2 }), Err(Error::TxInWrongState {
+			txid: 2,
+			action: TxType::Chargeback,
+			state: DisputeState::Resolved,
+		}));
+		assert_eq!(store.get_account(1).unwrap().summary().available, d("4"));
+	}
+
+	#[test]
+	fn account_order() {
+		let mut store = Store::new();
+		store.handle_deposit(1, 3, d("5")).unwrap();
+		store.handle_deposit(2, 1, d("7")).unwrap();
+		store.handle_deposit(3, 2, d("5")).unwrap();
+		store.handle_deposit(4, 4, d("1")).unwrap();
+		store.handle_dispute(4, 4).unwrap();
+		store.handle_chargeback(4, 4).unwrap();
+		store.handle_deposit(5, 3, d("1")).unwrap();
+		store.handle_dispute(3, 5).unwrap();
+		store.handle_chargeback(3, 5).unwrap();
+
+		let clients = |store: &Store, order| store.list_accounts_by(order).map(|a| a.client).collect::<Vec<_>>();
+		assert_eq!(store.list_accounts().map(|a| a.client).collect::<Vec<_>>(), [1, 2, 3, 4]);
+		assert_eq!(clients(&store, AccountOrder::Client), [1, 2, 3, 4]);
+		assert_eq!(clients(&store, AccountOrder::Total), [1, 2, 3, 4]);
+		assert_eq!(clients(&store, AccountOrder::LockedFirst), [3, 4, 1, 2]);
+
+		store.handle_withdrawal(6, 1, d("6")).unwrap();
+		assert_eq!(clients(&store, AccountOrder::Total), [2, 3, 1, 4]);
+	}
+
+	#[test]
+	fn rounding() {
+		let even = Precision { decimals: 2, rounding: Rounding::HalfEven };
+		let up = Precision { decimals: 2, rounding: Rounding::HalfUp };
+		for &(value, bankers, half_up) in &[
+			("1.005", "1.00", "1.01"),
+			("1.015", "1.02", "1.02"),
+			("1.0151", "1.02", "1.02"),
+			("-1.005", "-1.00", "-1.01"),
+			("-0.001", "0.00", "0.00"),
+			("3", "3.00", "3.00"),
+		] {
+			assert_eq!(even.apply(d(value)).to_string(), bankers, "{} half even", value);
+			assert_eq!(up.apply(d(value)).to_string(), half_up, "{} half up", value);
+		}
+		assert_eq!(Precision::default().apply(d("1.5")).to_string(), "1.5000");
+	}
+
+	#[test]
+	fn rounded_summaries() {
+		let precision = Precision { decimals: 1, rounding: Rounding::HalfEven };
+		let mut store = Store::new().with_precision(precision);
+		store.handle_deposit(1, 1, d("1.25")).unwrap();
+		store.handle_deposit(2, 1, d("1.25")).unwrap();
+		store.handle_dispute(1, 2).unwrap();
+
+		let summary = store.list_accounts().next().unwrap();
+		assert_eq!(summary.available.to_string(), "1.2");
+		assert_eq!(summary.held.to_string(), "1.2");
+		assert_eq!(summary.total.to_string(), "2.4");
+		assert_eq!(store.list_accounts_by(AccountOrder::Total).next().unwrap(), summary);
+	}
+
+	#[test]
+	fn withdrawal_disputes() {
+		const ACC: u16 = 1;
+		let summary = |store: &mut Store| {
+			let s = store.get_account(ACC).unwrap().summary();
+			(s.available, s.held, s.total, s.locked)
+		};
+
+		for &policy in &[DisputePolicy::AsDeposit, DisputePolicy::ByTxType] {
+			let mut store = Store::new().with_dispute_policy(policy);
+			store.handle_deposit(1, ACC, d("10")).unwrap();
+			store.handle_withdrawal(2, ACC, d("4")).unwrap();
+			store.handle_withdrawal(3, ACC, d("1")).unwrap();
+
+			// dispute and resolve the first withdrawal
+			store.handle_dispute(ACC, 2).unwrap();
+			let expected = match policy {
+				DisputePolicy::AsDeposit => (d("1"), d("4"), d("5"), false),
+				DisputePolicy::ByTxType => (d("5"), d("4"), d("9"), false),
+			};
+			assert_eq!(summary(&mut store), expected, "{:?}", policy);
+			store.handle_resolve(ACC, 2).unwrap();
+			assert_eq!(summary(&mut store), (d("5"), d("0"), d("5"), false), "{:?}", policy);
+
+			// dispute and charge back the second withdrawal
+			store.handle_dispute(ACC, 3).unwrap();
+			store.handle_chargeback(ACC, 3).unwrap();
+			let expected = match policy {
+				DisputePolicy::AsDeposit => (d("4"), d("0"), d("4"), true),
+				DisputePolicy::ByTxType => (d("6"), d("0"), d("6"), true),
+			};
+			assert_eq!(summary(&mut store), expected, "{:?}", policy);
+		}
+	}
+
+	#[test]
+	fn withdrawal_dispute_without_funds() {
+		// the money is gone, under the assignment's math it can't be held
+		let mut store = Store::new();
+		store.handle_deposit(1, 1, d("3")).unwrap();
+		store.handle_withdrawal(2, 1, d("3")).unwrap();
+		assert_eq!(store.handle_dispute(1, 2), Err(Error::InsufficientFunds { available: d("0"), required: d("3") }));
+
+		// but a provisional credit doesn't need any
+		let mut store = Store::new().with_dispute_policy(DisputePolicy::ByTxType);
+		store.handle_deposit(1, 1, d("3")).unwrap();
+		store.handle_withdrawal(2, 1, d("3")).unwrap();
+		store.handle_dispute(1, 2).unwrap();
+		store.handle_chargeback(1, 2).unwrap();
+		assert_eq!(store.get_account(1).unwrap().summary().available, d("3"));
+	}
+
+	/// Deposit 10 and withdraw 8 for client 1, and deposit 5 and withdraw 4
+	/// for client 2, then dispute both deposits.
+	fn dispute_after_withdrawal(store: &mut Store) -> [Result<(), Error>; 2] {
+		store.handle_deposit(1, 1, d("10")).unwrap();
+		store.handle_withdrawal(2, 1, d("8")).unwrap();
+		store.handle_deposit(3, 2, d("5")).unwrap();
+		store.handle_withdrawal(4, 2, d("4")).unwrap();
+		[store.handle_dispute(1, 1), store.handle_dispute(2, 3)]
+	}
+
+	#[test]
+	fn negative_reject() {
+		let mut store = Store::new();
+		assert_eq!(dispute_after_withdrawal(&mut store), [
+			Err(Error::InsufficientFunds { available: d("2"), required: d("10") }),
+			Err(Error::InsufficientFunds { available: d("1"), required: d("5") }),
+		]);
+		assert_eq!(store.negative_accounts().count(), 0);
+	}
+
+	#[test]
+	fn negative_allowed() {
+		let mut store = Store::new().with_negative_policy(NegativeBalancePolicy::AllowNegative);
+		assert_eq!(dispute_after_withdrawal(&mut store), [Ok(()), Ok(())]);
+
+		// a chargeback leaves the client owing the withdrawn funds
+		store.handle_chargeback(1, 1).unwrap();
+		assert_eq!(store.negative_accounts().collect::<Vec<_>>(), [
+			AccountSummary {
+				client: 1,
+				available: d("-8"),
+				held: d("0"),
+				total: d("-8"),
+				locked: true,
+			},
+			AccountSummary {
+				client: 2,
+				available: d("-4"),
+				held: d("5"),
+				total: d("1"),
+				locked: false,
+			},
+		]);
+
+		// no withdrawals on a negative balance
+		assert_eq!(store.handle_withdrawal(5, 2, d("1")), Err(Error::InsufficientFunds { available: d("-4"), required: d("1") }));
+	}
+
+	#[test]
+	fn negative_credit_limit() {
+		let mut store = Store::new()
+			.with_negative_policy(NegativeBalancePolicy::CreditLimit { default: d("8") })
+			.with_credit_limit(2, d("2"));
+		assert_eq!(dispute_after_withdrawal(&mut store), [
+			Ok(()),
+			Err(Error::CreditLimitExceeded { available: d("1"), limit: d("2"), required: d("5") }),
+		]);
+		assert_eq!(store.negative_accounts().map(|a| a.client).collect::<Vec<_>>(), [1]);
+		assert!(store.get_account(1).unwrap().summary().is_negative());
+		assert!(!store.get_account(2).unwrap().summary().is_negative());
+	}
+
+	#[test]
+	fn negative_below_precision() {
+		let mut store = Store::new()
+			.with_negative_policy(NegativeBalancePolicy::AllowNegative)
+			.with_precision(Precision { decimals: 2, rounding: Rounding::HalfEven });
+		store.handle_deposit(1, 1, d("0.0001")).unwrap();
+		store.handle_withdrawal(2, 1, d("0.0001")).unwrap();
+		store.handle_dispute(1, 1).unwrap();
+		assert_eq!(store.negative_accounts().collect::<Vec<_>>(), store.list_accounts().collect::<Vec<_>>());
+		assert_eq!(store.negative_accounts().next().unwrap().available, d("0.00"));
+	}
+
+	fn event_txs() -> Vec<Transaction> {
+		vec![
+			Transaction::Deposit { txid: 1, client: 1, amount: d("10") },
+			Transaction::Withdrawal { txid: 2, client: 1, amount: d("3") },
+			Transaction::Deposit { txid: 3, client: 2, amount: d("5") },
+			// refused, not recorded
+			Transaction::Withdrawal { txid: 4, client: 2, amount: d("50") },
+			Transaction::Dispute { client: 1, txid: 2 },
+			Transaction::Resolve { client: 1, txid: 2 },
+			Transaction::Dispute { client: 2, txid: 3 },
+			Transaction::Chargeback { client: 2, txid: 3 },
+		]
+	}
+
+	#[test]
+	fn events() {
+		let mut store = Store::new().with_event_log();
+		for tx in event_txs() {
+			let _ = store.apply(tx);
+		}
+		let events = store.events();
+		assert_eq!(events.iter().map(|e| e.seq).collect::<Vec<_>>(), [1, 2, 3, 4, 5, 6, 7]);
+		assert_eq!(events[1], Event {
+			seq: 2,
+			tx: Transaction::Withdrawal { txid: 2, client: 1, amount: d("3") },
+			client: 1,
+			available: d("-3"),
+			held: d("0"),
+			locked: false,
+		});
+		assert_eq!((events[3].available, events[3].held), (d("-3"), d("3")));
+		assert_eq!((events[6].available, events[6].held, events[6].locked), (d("0"), d("-5"), true));
+
+		// the balances add up to the current state
+		for account in store.list_accounts() {
+			assert_eq!(store.balance_at(account.client, u64::MAX), Some(account));
+		}
+		let balance = |seq| store.balance_at(1, seq).map(|a| (a.available, a.held));
+		assert_eq!(balance(0), None);
+		assert_eq!(balance(1), Some((d("10"), d("0"))));
+		assert_eq!(balance(3), Some((d("7"), d("0"))));
+		assert_eq!(balance(4), Some((d("4"), d("3"))));
+		assert_eq!(balance(5), Some((d("7"), d("0"))));
+		assert_eq!(store.balance_at(2, 6).map(|a| a.locked), Some(false));
+		assert_eq!(store.balance_at(2, 7).map(|a| a.locked), Some(true));
+		assert_eq!(store.balance_at(3, 7), None);
+	}
+
+	#[test]
+	fn events_apply_to_owner() {
+		let mut store = Store::new().with_event_log().with_ownership_policy(OwnershipPolicy::ApplyToOwner);
+		store.handle_deposit(1, 1, d("5")).unwrap();
+		store.handle_dispute(2, 1).unwrap();
+		assert_eq!(store.events()[1].client, 1);
+		assert_eq!(store.balance_at(1, 2).map(|a| a.held), Some(d("5")));
+		assert_eq!(store.balance_at(2, 2), None);
+	}
+
+	#[test]
+	fn replay() {
+		let mut store = Store::new().with_event_log();
+		for tx in event_txs() {
+			let _ = store.apply(tx);
+		}
+
+		let mut replayed = Store::new().with_event_log();
+		replayed.replay(store.events().iter().copied()).unwrap();
+		assert_eq!(replayed.list_accounts().collect::<Vec<_>>(), store.list_accounts().collect::<Vec<_>>());
+		let mut txs = store.transactions().collect::<Vec<_>>();
+		let mut replayed_txs = replayed.transactions().collect::<Vec<_>>();
+		txs.sort_by_key(|tx| tx.txid);
+		replayed_txs.sort_by_key(|tx| tx.txid);
+		assert_eq!(replayed_txs, txs);
+		assert_eq!(replayed.events(), store.events());
+
+		// events that don't fit the state are refused
+		assert_eq!(replayed.replay(store.events()[..1].iter().copied()), Err(Error::DuplicateTx { txid: 1 }));
+	}
+
+	#[test]
+	fn no_event_log() {
+		let mut store = Store::new();
+		store.handle_deposit(1, 1, d("5")).unwrap();
+		assert!(store.events().is_empty());
+		assert_eq!(store.balance_at(1, 1), None);
+	}
+
+	#[test]
+	fn error_display() {
+		let mut store = Store::new();
+		store.handle_deposit(1, 1, d("2.5")).unwrap();
+		let err = store.handle_withdrawal(2, 1, d("3")).unwrap_err();
+		assert_eq!(err.to_string(), "insufficient funds: 3 required, 2.5 available");
+		store.handle_dispute(1, 1).unwrap();
+		store.handle_chargeback(1, 1).unwrap();
+		let err = store.handle_resolve(1, 1).unwrap_err();
+		assert_eq!(err.to_string(), "can't resolve tx 1, it is charged_back");
+	}
+
+	#[test]
+	fn overflow() {
+		let mut store = Store::new();
+		store.handle_deposit(1, 1, Decimal::MAX).unwrap();
+		let err = store.handle_deposit(2, 1, d("1")).unwrap_err();
+		assert_eq!(err, Error::Overflow { client: 1, available: d("1"), held: d("0") });
+		assert_eq!(store.get_account(1).unwrap().available, Decimal::MAX);
+		assert!(store.backend.get_tx(2).is_none());
+	}
+
+	#[test]
+	fn total_overflow() {
+		// a provisional credit adds to the total without taking from available
+		let mut store = Store::new().with_dispute_policy(DisputePolicy::ByTxType);
+		store.handle_deposit(1, 1, Decimal::MAX).unwrap();
+		store.handle_withdrawal(2, 1, d("10")).unwrap();
+		store.handle_dispute(1, 2).unwrap();
+		let err = store.handle_deposit(3, 1, d("10")).unwrap_err();
+		assert_eq!(err, Error::Overflow { client: 1, available: d("10"), held: d("0") });
+		assert_eq!(store.get_account(1).unwrap().summary().total, Decimal::MAX);
+		assert!(store.audit().is_empty());
+	}
+
+	#[test]
+	fn not_disputable() {
+		let mut store = Store::new();
+		store.backend.insert_tx(TxRecord {
+			txid: 1,
+			tp: TxType::Chargeback,
+			client: 1,
+			amount: d("1"),
+			dispute_state: DisputeState::Normal,
+		}).unwrap();
+		let err = store.handle_dispute(1, 1).unwrap_err();
+		assert_eq!(err, Error::NotDisputable { txid: 1, tp: TxType::Chargeback });
+		assert_eq!(err.to_string(), "tx 1 is a chargeback and can't be disputed");
+	}
 }
@@ -0,0 +1,668 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+use std::{env, fs, thread};
+
+fn fixture(name: &str) -> PathBuf {
+	Path::new(env!("CARGO_MANIFEST_DIR")).join(name)
+}
+
+/// A path in the temp dir that is unique to this test run
+fn temp_path(name: &str) -> PathBuf {
+	env::temp_dir().join(format!("kraken-{}-{}", std::process::id(), name))
+}
+
+fn run_with(args: &[&str]) -> Output {
+	let output = Command::new(env!("CARGO_BIN_EXE_kraken"))
+		.args(args)
+		.output()
+		.expect("failed to run binary");
+	assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+	output
+}
+
+fn lines(output: &Output) -> Vec<String> {
+	let stdout = String::from_utf8(output.stdout.clone()).expect("non-utf8 output");
+	stdout.lines().map(String::from).collect()
+}
+
+/// Run the binary on a fixture and return the output lines
+fn run(name: &str) -> Vec<String> {
+	lines(&run_with(&[fixture(name).to_str().unwrap()]))
+}
+
+#[test]
+fn demo() {
+	assert_eq!(run("demo.csv"), [
+		"client,available,held,total,locked",
+		"1,1.5000,0.0000,1.5000,false",
+		"2,2.0000,0.0000,2.0000,false",
+	]);
+}
+
+#[test]
+fn schema() {
+	assert_eq!(run("tests/fixtures/schema.csv"), [
+		"client,available,held,total,locked",
+		"1,8.5000,0.0000,8.5000,false",
+		"2,3.0000,0.0000,3.0000,true",
+		"3,2.0000,0.0000,2.0000,false",
+	]);
+}
+
+#[test]
+fn rejects() {
+	let rejects = temp_path("rejects.csv");
+	let output = run_with(&[
+		fixture("tests/fixtures/schema.csv").to_str().unwrap(),
+		"--rejects", rejects.to_str().unwrap(),
+	]);
+	assert_eq!(lines(&output), run("tests/fixtures/schema.csv"));
+
+	let written = fs::read_to_string(&rejects).expect("rejects file not written");
+	fs::remove_file(&rejects).unwrap();
+	assert_eq!(written.lines().collect::<Vec<_>>(), [
+		"line,type,client,tx,amount,reason",
+		"6,dispute,1,1,,insufficient_funds",
+		"8,resolve,1,1,,tx_in_wrong_state",
+		"10,deposit,3,5,,missing_amount",
+		"11,teleport,3,6,1,unknown_type",
+		"12,deposit,3,7,1.23456,excessive_precision",
+		"14,deposit,abc,9,1,malformed_row",
+	]);
+
+	let stderr = String::from_utf8(output.stderr).expect("non-utf8 output");
+	assert_eq!(stderr.lines().collect::<Vec<_>>(), [
+		"rejected 6 rows",
+		"  excessive_precision: 1",
+		"  insufficient_funds: 1",
+		"  malformed_row: 1",
+		"  missing_amount: 1",
+		"  tx_in_wrong_state: 1",
+		"  unknown_type: 1",
+	]);
+}
+
+#[test]
+fn sort() {
+	let input = fixture("tests/fixtures/schema.csv");
+	let sorted = |order| lines(&run_with(&[input.to_str().unwrap(), "--sort", order]));
+	assert_eq!(sorted("client"), run("tests/fixtures/schema.csv"));
+	assert_eq!(sorted("total"), [
+		"client,available,held,total,locked",
+		"1,8.5000,0.0000,8.5000,false",
+		"2,3.0000,0.0000,3.0000,true",
+		"3,2.0000,0.0000,2.0000,false",
+	]);
+	assert_eq!(sorted("locked"), [
+		"client,available,held,total,locked",
+		"2,3.0000,0.0000,3.0000,true",
+		"1,8.5000,0.0000,8.5000,false",
+		"3,2.0000,0.0000,2.0000,false",
+	]);
+}
+
+#[test]
+fn precision() {
+	let input = fixture("tests/fixtures/rounding.csv");
+	let rounded = |rounding| lines(&run_with(&[input.to_str().unwrap(), "--decimals", "1", "--rounding", rounding]));
+	assert_eq!(rounded("half-up"), [
+		"client,available,held,total,locked",
+		"1,0.3,0.0,0.3,false",
+		"2,0.4,0.0,0.4,false",
+	]);
+	assert_eq!(rounded("half-even"), [
+		"client,available,held,total,locked",
+		"1,0.2,0.0,0.2,false",
+		"2,0.4,0.0,0.4,false",
+	]);
+}
+
+#[test]
+fn amounts() {
+	let rejects = temp_path("amounts-rejects.csv");
+	let output = run_with(&[
+		fixture("tests/fixtures/amounts.csv").to_str().unwrap(),
+		"--rejects", rejects.to_str().unwrap(),
+	]);
+	assert_eq!(lines(&output), [
+		"client,available,held,total,locked",
+		"1,12345678901234567.1234,0.0000,12345678901234567.1234,false",
+		"2,18446744073709551616.0000,0.0000,18446744073709551616.0000,false",
+		"3,100000000000000000000.0000,0.0000,100000000000000000000.0000,false",
+		"4,1.5025,0.0000,1.5025,false",
+	]);
+
+	let written = fs::read_to_string(&rejects).expect("rejects file not written");
+	fs::remove_file(&rejects).unwrap();
+	assert_eq!(written.lines().collect::<Vec<_>>(), [
+		"line,type,client,tx,amount,reason",
+		"2,deposit,1,1,1.00000000000000001,excessive_precision",
+		"6,deposit,4,5,1.000000000000000000000000000001,excessive_precision",
+		"7,deposit,4,6,79228162514264337593543950336,malformed_row",
+		"9,deposit,4,8,ten,malformed_row",
+	]);
+}
+
+#[test]
+fn retention() {
+	let rejects = temp_path("retention-rejects.csv");
+	let output = run_with(&[
+		fixture("tests/fixtures/schema.csv").to_str().unwrap(),
+		"--retention", "2",
+		"--rejects", rejects.to_str().unwrap(),
+	]);
+	assert_eq!(lines(&output), run("tests/fixtures/schema.csv"));
+
+	let written = fs::read_to_string(&rejects).expect("rejects file not written");
+	fs::remove_file(&rejects).unwrap();
+	assert_eq!(written.lines().filter(|l| l.ends_with(",tx_expired")).collect::<Vec<_>>(), [
+		"6,dispute,1,1,,tx_expired",
+		"8,resolve,1,1,,tx_expired",
+	]);
+}
+
+#[test]
+fn tables() {
+	let dir = temp_path("tables");
+	let output = run_with(&[fixture("demo.csv").to_str().unwrap(), "--tables", dir.to_str().unwrap()]);
+	assert_eq!(lines(&output), run("demo.csv"));
+
+	let sql = lines(&run_with(&["export-sql", dir.to_str().unwrap()]));
+	fs::remove_dir_all(&dir).unwrap();
+	assert_eq!(sql.iter().filter(|l| l.starts_with("INSERT INTO accounts ")).collect::<Vec<_>>(), [
+		"INSERT INTO accounts VALUES (1, '1.5', '0', '1.5', 0);",
+		"INSERT INTO accounts VALUES (2, '2.0', '0', '2.0', 0);",
+	]);
+	assert_eq!(sql.iter().filter(|l| l.starts_with("INSERT INTO txs ")).count(), 4);
+	assert_eq!(sql.last().map(String::as_str), Some("COMMIT;"));
+}
+
+#[test]
+fn threads() {
+	// pseudo-random rows over a few clients, with lots of disputes and reused txids
+	let mut seed = 1u64;
+	let mut next = |n: u64| {
+		seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+		(seed >> 33) % n
+	};
+	let mut csv = String::from("type,client,tx,amount\n");
+	for _ in 0..5000 {
+		let (client, tx, amount) = (next(20), next(500), next(10000) as f64 / 100.0);
+		match next(10) {
+			0..=3 => csv += &format!("deposit,{},{},{}\n", client, tx, amount),
+			4..=5 => csv += &format!("withdrawal,{},{},{}\n", client, tx, amount),
+			6..=7 => csv += &format!("dispute,{},{},\n", client, tx),
+			8 => csv += &format!("resolve,{},{},\n", client, tx),
+			_ => csv += &format!("chargeback,{},{},\n", client, tx),
+		}
+	}
+	let input = temp_path("threads.csv");
+	fs::write(&input, csv).unwrap();
+
+	let run_threads = |threads: &str| {
+		let rejects = temp_path(&format!("threads-rejects-{}.csv", threads));
+		let output = run_with(&[
+			input.to_str().unwrap(),
+			"--threads", threads,
+			"--sort", "total",
+			"--rejects", rejects.to_str().unwrap(),
+		]);
+		let written = fs::read_to_string(&rejects).expect("rejects file not written");
+		fs::remove_file(&rejects).unwrap();
+		(output.stdout, output.stderr, written)
+	};
+	let single = run_threads("1");
+	for threads in &["2", "4", "7"] {
+		assert!(single == run_threads(threads), "output differs with {} threads", threads);
+	}
+	fs::remove_file(&input).unwrap();
+}
+
+/// A `kraken serve` process, killed when dropped
+struct Server {
+	child: Child,
+	addr: String,
+}
+
+impl Server {
+	fn start(args: &[&str]) -> Server {
+		let mut child = Command::new(env!("CARGO_BIN_EXE_kraken"))
+			.args(args)
+			.stderr(Stdio::piped())
+			.spawn()
+			.expect("failed to run binary");
+		let mut line = String::new();
+		BufReader::new(child.stderr.as_mut().unwrap()).read_line(&mut line).unwrap();
+		let addr = line.trim().strip_prefix("listening on ").expect("server didn't start").to_string();
+		Server { child, addr }
+	}
+}
+
+impl Drop for Server {
+	fn drop(&mut self) {
+		let _ = self.child.kill();
+		let _ = self.child.wait();
+	}
+}
+
+/// Send requests on a connection and read back `responses` lines
+fn request(stream: impl Read + Write, requests: &str, responses: usize) -> Vec<String> {
+	let mut reader = BufReader::new(stream);
+	reader.get_mut().write_all(requests.as_bytes()).unwrap();
+	(0..responses)
+		.map(|_| {
+			let mut line = String::new();
+			reader.read_line(&mut line).unwrap();
+			line.trim_end_matches('\n').to_string()
+		})
+		.collect()
+}
+
+#[test]
+fn serve_tcp() {
+	let server = Server::start(&["serve", "127.0.0.1:0"]);
+	let connect = || TcpStream::connect(&server.addr).unwrap();
+
+	// several clients at once, each with their own accounts
+	let workers = (1..=4)
+		.map(|client| {
+			let stream = connect();
+			thread::spawn(move || {
+				let mut requests = String::from("type,client,tx,amount\n");
+				for i in 0..50 {
+					requests += &format!("deposit,{},{},1.5\n", client, client * 1000 + i);
+				}
+				requests += &format!("withdrawal,{},{},100\n", client, client * 1000 + 50);
+				let responses = request(stream, &requests, 51);
+				assert!(responses[..50].iter().all(|r| r.starts_with("ok,")), "{:?}", responses);
+				assert_eq!(responses[50], format!("rejected,{},insufficient_funds", client * 1000 + 50));
+			})
+		})
+		.collect::<Vec<_>>();
+	for worker in workers {
+		worker.join().unwrap();
+	}
+
+	assert_eq!(request(connect(), concat!(
+		"{\"type\": \"dispute\", \"client\": 1, \"tx\": 1000}\n",
+		"{\"type\": \"deposit\", \"client\": 5, \"tx\": 1000, \"amount\": \"2\"}\n",
+		"{\"type\": \"deposit\", \"client\": 5\n",
+		"teleport,5,1,1\n",
+		"accounts\n",
+		"account 1\n",
+	), 13), [
+		r#"{"tx":1000,"result":"ok"}"#,
+		r#"{"tx":1000,"result":"rejected","reason":"duplicate_tx"}"#,
+		r#"{"tx":null,"result":"rejected","reason":"malformed_row"}"#,
+		"rejected,1,unknown_type",
+		"client,available,held,total,locked",
+		"1,73.5000,1.5000,75.0000,false",
+		"2,75.0000,0.0000,75.0000,false",
+		"3,75.0000,0.0000,75.0000,false",
+		"4,75.0000,0.0000,75.0000,false",
+		"",
+		"client,available,held,total,locked",
+		"1,73.5000,1.5000,75.0000,false",
+		"",
+	]);
+}
+
+#[cfg(unix)]
+#[test]
+fn serve_unix() {
+	let path = temp_path("serve.sock");
+	let _ = fs::remove_file(&path);
+	let server = Server::start(&["serve", &format!("unix:{}", path.display())]);
+	let path = server.addr.strip_prefix("unix:").unwrap().to_string();
+
+	let connect = || UnixStream::connect(&path).unwrap();
+	assert_eq!(request(connect(), "deposit,1,1,2.5\n", 1), ["ok,1"]);
+	assert_eq!(request(connect(), "account 1\n", 3), [
+		"client,available,held,total,locked",
+		"1,2.5000,0.0000,2.5000,false",
+		"",
+	]);
+	drop(server);
+	fs::remove_file(&path).unwrap();
+}
+
+/// Send an HTTP request and return the status line and body
+fn http(stream: &mut TcpStream, method: &str, path: &str, body: &str) -> (String, String) {
+	write!(stream, "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}", method, path, body.len(), body).unwrap();
+	let mut reader = BufReader::new(stream);
+	let mut status = String::new();
+	reader.read_line(&mut status).unwrap();
+	let mut length = 0;
+	loop {
+		let mut header = String::new();
+		reader.read_line(&mut header).unwrap();
+		match header.trim_end().split_once(": ") {
+			Some((name, value)) if name.eq_ignore_ascii_case("content-length") => length = value.parse().unwrap(),
+			Some(_) => {},
+			None => break,
+		}
+	}
+	let mut body = vec![0; length];
+	reader.read_exact(&mut body).unwrap();
+	(status.trim_end().to_string(), String::from_utf8(body).unwrap().trim_end().to_string())
+}
+
+#[test]
+fn http_api() {
+	let server = Server::start(&["http", "127.0.0.1:0"]);
+	let mut stream = TcpStream::connect(&server.addr).unwrap();
+	let mut request = |method: &str, path: &str, body: &str| http(&mut stream, method, path, body);
+
+	assert_eq!(request("POST", "/transactions", r#"{"type":"deposit","client":1,"tx":1,"amount":"2.5"}"#), (
+		"HTTP/1.1 201 Created".to_string(),
+		r#"{"tx":1,"result":"applied"}"#.to_string(),
+	));
+	assert_eq!(request("POST", "/transactions", r#"{"type":"withdrawal","client":1,"tx":2,"amount":3}"#), (
+		"HTTP/1.1 422 Unprocessable Entity".to_string(),
+		r#"{"error":"insufficient_funds","client":1,"txid":2,"available":2.5,"required":3,"message":"insufficient funds: 3 required, 2.5 available"}"#.to_string(),
+	));
+	assert_eq!(request("POST", "/transactions", r#"{"type":"deposit","client":2,"tx":1,"amount":1}"#).0, "HTTP/1.1 409 Conflict");
+	assert_eq!(request("POST", "/transactions", r#"{"type":"dispute","client":2,"tx":1}"#), (
+		"HTTP/1.1 403 Forbidden".to_string(),
+		r#"{"error":"client_mismatch","client":2,"txid":1,"expected":1,"got":2,"message":"tx 1 belongs to client 1, not 2"}"#.to_string(),
+	));
+	assert_eq!(request("POST", "/transactions", r#"{"type":"dispute","client":1,"tx":7}"#).0, "HTTP/1.1 404 Not Found");
+	assert_eq!(request("POST", "/transactions", r#"{"type":"dispute","client":1,"tx":1}"#).0, "HTTP/1.1 201 Created");
+	assert_eq!(request("POST", "/transactions", "deposit,1,3,1").0, "HTTP/1.1 400 Bad Request");
+
+	assert_eq!(request("GET", "/accounts/1", ""), (
+		"HTTP/1.1 200 OK".to_string(),
+		r#"{"client":1,"available":0.0000,"held":2.5000,"total":2.5000,"locked":false}"#.to_string(),
+	));
+	assert_eq!(request("GET", "/accounts", "").1, r#"[{"client":1,"available":0.0000,"held":2.5000,"total":2.5000,"locked":false}]"#);
+	assert_eq!(request("GET", "/transactions/1", "").1, r#"{"tx":1,"type":"deposit","client":1,"amount":2.5,"state":"disputed"}"#);
+	assert_eq!(request("GET", "/transactions/2", ""), (
+		"HTTP/1.1 404 Not Found".to_string(),
+		r#"{"error":"tx_not_found","txid":2,"message":"tx 2 not found"}"#.to_string(),
+	));
+	assert_eq!(request("GET", "/accounts/9", "").0, "HTTP/1.1 404 Not Found");
+	assert_eq!(request("GET", "/accounts/x", "").0, "HTTP/1.1 400 Bad Request");
+	assert_eq!(request("DELETE", "/accounts", "").0, "HTTP/1.1 405 Method Not Allowed");
+	assert_eq!(request("GET", "/nothing", "").0, "HTTP/1.1 404 Not Found");
+}
+
+#[test]
+fn jsonl_input() {
+	let rejects = temp_path("jsonl-rejects.csv");
+	let output = run_with(&[
+		fixture("tests/fixtures/schema.jsonl").to_str().unwrap(),
+		"--input-format", "jsonl",
+		"--rejects", rejects.to_str().unwrap(),
+	]);
+	assert_eq!(lines(&output), run("tests/fixtures/schema.csv"));
+
+	// the same rejects as for CSV, with line numbers of the JSON file
+	let written = fs::read_to_string(&rejects).expect("rejects file not written");
+	fs::remove_file(&rejects).unwrap();
+	assert_eq!(written.lines().collect::<Vec<_>>(), [
+		"line,type,client,tx,amount,reason",
+		"6,dispute,1,1,,insufficient_funds",
+		"8,resolve,1,1,,tx_in_wrong_state",
+		"10,deposit,3,5,,missing_amount",
+		"11,teleport,3,6,1,unknown_type",
+		"12,deposit,3,7,1.23456,excessive_precision",
+		"14,deposit,abc,9,1,malformed_row",
+		"15,,,,,malformed_row",
+	]);
+}
+
+#[test]
+fn output_formats() {
+	let input = fixture("demo.csv");
+	let output = |format| lines(&run_with(&[input.to_str().unwrap(), "--output-format", format]));
+	assert_eq!(output("csv"), run("demo.csv"));
+	assert_eq!(output("jsonl"), [
+		r#"{"client":1,"available":1.5000,"held":0.0000,"total":1.5000,"locked":false}"#,
+		r#"{"client":2,"available":2.0000,"held":0.0000,"total":2.0000,"locked":false}"#,
+	]);
+	assert_eq!(output("json"), [
+		"[",
+		r#"  {"client":1,"available":1.5000,"held":0.0000,"total":1.5000,"locked":false},"#,
+		r#"  {"client":2,"available":2.0000,"held":0.0000,"total":2.0000,"locked":false}"#,
+		"]",
+	]);
+}
+
+#[test]
+fn stdin() {
+	let mut child = Command::new(env!("CARGO_BIN_EXE_kraken"))
+		.arg("-")
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		.spawn()
+		.expect("failed to run binary");
+	let demo = fs::read(fixture("demo.csv")).unwrap();
+	child.stdin.take().unwrap().write_all(&demo).unwrap();
+	let output = child.wait_with_output().unwrap();
+	assert!(output.status.success());
+	assert_eq!(lines(&output), run("demo.csv"));
+}
+
+#[test]
+fn gzip_input() {
+	// .gz inputs are decompressed by the gzip program, which may be missing
+	if Command::new("gzip").arg("--version").output().is_err() {
+		eprintln!("skipping gzip_input, gzip is not on the PATH");
+		return;
+	}
+	assert_eq!(run("tests/fixtures/demo.csv.gz"), run("demo.csv"));
+}
+
+#[test]
+fn multiple_inputs() {
+	let a = fixture("tests/fixtures/merge-a.csv");
+	let b = fixture("tests/fixtures/merge-b.csv");
+	let (a, b) = (a.to_str().unwrap(), b.to_str().unwrap());
+
+	// one after the other, so the withdrawal comes after both deposits
+	assert_eq!(lines(&run_with(&[a, b])), [
+		"client,available,held,total,locked",
+		"1,3.0000,0.0000,3.0000,false",
+		"2,0.0000,1.0000,1.0000,false",
+	]);
+
+	let rejects = temp_path("merge-rejects.csv");
+	let merged = run_with(&[a, b, "--merge", "--rejects", rejects.to_str().unwrap()]);
+	assert_eq!(lines(&merged), [
+		"client,available,held,total,locked",
+		"1,15.0000,0.0000,15.0000,false",
+		"2,0.0000,1.0000,1.0000,false",
+	]);
+	let written = fs::read_to_string(&rejects).expect("rejects file not written");
+	fs::remove_file(&rejects).unwrap();
+	assert_eq!(written.lines().collect::<Vec<_>>(), [
+		"file,line,type,client,tx,amount,reason".to_string(),
+		format!("{},2,withdrawal,1,2,12,insufficient_funds", b),
+	]);
+
+	// the dispute comes right after deposit 900, so tx 950 doesn't exist yet
+	let a = fixture("tests/fixtures/merge-dispute-a.csv");
+	let b = fixture("tests/fixtures/merge-dispute-b.csv");
+	let merged = run_with(&[b.to_str().unwrap(), a.to_str().unwrap(), "--merge"]);
+	assert_eq!(lines(&merged), [
+		"client,available,held,total,locked",
+		"1,6.0000,0.0000,6.0000,false",
+		"2,3.0000,0.0000,3.0000,false",
+	]);
+}
+
+/// Run the binary and return its exit code and stderr
+fn run_status(args: &[&str]) -> (Option<i32>, String) {
+	let output = Command::new(env!("CARGO_BIN_EXE_kraken"))
+		.args(args)
+		.output()
+		.expect("failed to run binary");
+	(output.status.code(), String::from_utf8(output.stderr).expect("non-utf8 output"))
+}
+
+#[test]
+fn exit_codes() {
+	let help = Command::new(env!("CARGO_BIN_EXE_kraken")).arg("--help").output().unwrap();
+	assert!(help.status.success());
+	assert!(String::from_utf8(help.stdout).unwrap().contains("Exit codes:"));
+
+	assert_eq!(run_status(&[]).0, Some(2));
+	assert_eq!(run_status(&["demo.csv", "--sort", "sideways"]).0, Some(2));
+	assert_eq!(run_status(&["replay"]).0, Some(2));
+	assert_eq!(run_status(&["demo.csv", "--negative", "allow", "--credit-limit", "5"]).0, Some(2));
+	assert_eq!(run_status(&["demo.csv", "--retention", "0"]).0, Some(2));
+	let (code, stderr) = run_status(&["demo.csv", "--snapshot-interval", "10"]);
+	assert_eq!((code, stderr.as_str()), (Some(2), "--snapshot-interval needs --state\n"));
+	let (code, stderr) = run_status(&["demo.csv", "--threads", "2", "--spill", "spill.bin"]);
+	assert_eq!((code, stderr.as_str()), (Some(2), "--retention and --spill can't be combined with --threads\n"));
+
+	let (code, stderr) = run_status(&["tests/fixtures/missing.csv"]);
+	assert_eq!(code, Some(1));
+	assert!(stderr.starts_with("failed to open tests/fixtures/missing.csv"), "{}", stderr);
+
+	let input = temp_path("not-utf8.csv");
+	fs::write(&input, b"type,client,tx,amount\ndeposit,1,1,\xff\n").unwrap();
+	assert_eq!(run_status(&[input.to_str().unwrap()]).0, Some(3));
+	fs::remove_file(&input).unwrap();
+}
+
+#[test]
+fn validate() {
+	let (code, stderr) = run_status(&["validate", fixture("demo.csv").to_str().unwrap()]);
+	assert_eq!((code, stderr.as_str()), (Some(0), "0 of 5 rows are invalid\n"));
+
+	let (code, stderr) = run_status(&["validate", fixture("tests/fixtures/schema.csv").to_str().unwrap()]);
+	assert_eq!(code, Some(3));
+	assert_eq!(stderr.lines().collect::<Vec<_>>(), [
+		"4 of 13 rows are invalid",
+		"  excessive_precision: 1",
+		"  malformed_row: 1",
+		"  missing_amount: 1",
+		"  unknown_type: 1",
+	]);
+}
+
+#[test]
+fn state() {
+	let dir = temp_path("state");
+	let _ = fs::remove_dir_all(&dir);
+	let dir = dir.to_str().unwrap();
+	let a = fixture("tests/fixtures/merge-a.csv");
+	let b = fixture("tests/fixtures/merge-b.csv");
+
+	// processing in two runs gives the same as in one
+	run_with(&["process", a.to_str().unwrap(), "--state", dir, "--snapshot-interval", "1"]);
+	let second = run_with(&["process", b.to_str().unwrap(), "--state", dir]);
+	assert_eq!(lines(&second), lines(&run_with(&[a.to_str().unwrap(), b.to_str().unwrap()])));
+	assert_eq!(lines(&run_with(&["replay", dir])), lines(&second));
+
+	assert_eq!(lines(&run_with(&["inspect", "2", "--state", dir])), [
+		"client,available,held,total,locked",
+		"2,0.0000,1.0000,1.0000,false",
+		"",
+		"tx,type,amount,state",
+		"4,deposit,1,disputed",
+	]);
+	assert_eq!(lines(&run_with(&["inspect", "2", "--state", dir, "--output-format", "jsonl"])), [
+		r#"{"client":2,"available":0.0000,"held":1.0000,"total":1.0000,"locked":false}"#,
+		r#"{"tx":4,"type":"deposit","client":2,"amount":1,"state":"disputed"}"#,
+	]);
+
+	// a partial last log entry is left by a crash and dropped, anything
+	// else that doesn't read back means the state is damaged
+	let log = Path::new(dir).join("wal.log");
+	fs::write(&log, "1 deposit 1 99 5\n2 depo").unwrap();
+	run_with(&["replay", dir]);
+	fs::write(&log, "1 deposit 1 99 5\ngarbage\n").unwrap();
+	let (code, stderr) = run_status(&["replay", dir]);
+	assert_eq!(code, Some(4), "{}", stderr);
+	fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn policy_flags() {
+	let input = temp_path("policies.csv");
+	fs::write(&input, "type,client,tx,amount\ndeposit,1,1,5\ndeposit,1,1,5\nwithdrawal,1,2,4\ndispute,2,1,\n").unwrap();
+	let input = input.to_str().unwrap();
+
+	assert_eq!(lines(&run_with(&[input])), [
+		"client,available,held,total,locked",
+		"1,1.0000,0.0000,1.0000,false",
+	]);
+	// the dispute goes to the owner, and may make the funds negative
+	assert_eq!(lines(&run_with(&[input, "--ownership", "apply-to-owner", "--negative", "allow"])), [
+		"client,available,held,total,locked",
+		"1,-4.0000,5.0000,1.0000,false",
+	]);
+	assert_eq!(lines(&run_with(&[input, "--ownership", "apply-to-owner", "--client-credit-limit", "1=3"])), [
+		"client,available,held,total,locked",
+		"1,1.0000,0.0000,1.0000,false",
+	]);
+	assert_eq!(lines(&run_with(&[input, "--ownership", "apply-to-owner", "--credit-limit", "4"])), [
+		"client,available,held,total,locked",
+		"1,-4.0000,5.0000,1.0000,false",
+	]);
+	fs::remove_file(input).unwrap();
+}
+
+#[test]
+fn statement() {
+	let a = fixture("tests/fixtures/merge-a.csv");
+	let b = fixture("tests/fixtures/merge-b.csv");
+	let statement = |client, format| {
+		lines(&run_with(&["statement", client, a.to_str().unwrap(), b.to_str().unwrap(), "--output-format", format]))
+	};
+
+	assert_eq!(statement("1", "csv"), [
+		"seq,type,tx,amount,available,held,total,locked",
+		"1,deposit,1,10.0000,10.0000,0.0000,10.0000,false",
+		"2,deposit,3,5.0000,15.0000,0.0000,15.0000,false",
+		"3,withdrawal,2,12.0000,3.0000,0.0000,3.0000,false",
+	]);
+	// merged, the withdrawal comes before the second deposit and is refused
+	let merged = run_with(&["statement", "1", a.to_str().unwrap(), b.to_str().unwrap(), "--merge"]);
+	assert_eq!(lines(&merged).len(), 3);
+	assert_eq!(statement("2", "jsonl"), [
+		r#"{"seq":4,"type":"deposit","tx":4,"amount":1.0000,"available":1.0000,"held":0.0000,"total":1.0000,"locked":false}"#,
+		r#"{"seq":5,"type":"dispute","tx":4,"amount":1.0000,"available":0.0000,"held":1.0000,"total":1.0000,"locked":false}"#,
+	]);
+	assert_eq!(statement("3", "json"), ["[]"]);
+
+	let (code, _) = run_status(&["statement", "1", a.to_str().unwrap(), "--state", "x"]);
+	assert_eq!(code, Some(2));
+}
+
+#[test]
+fn trial_balance() {
+	let a = fixture("tests/fixtures/merge-a.csv");
+	let b = fixture("tests/fixtures/merge-b.csv");
+	let (a, b) = (a.to_str().unwrap(), b.to_str().unwrap());
+
+	let output = run_with(&[a, b, "--trial-balance"]);
+	assert_eq!(lines(&output), lines(&run_with(&[a, b])));
+	assert_eq!(String::from_utf8_lossy(&output.stderr), format!(
+		"trial balance after {}: ok\ntrial balance after {}: ok\n", a, b,
+	));
+
+	let output = run_with(&[a, b, "--merge", "--trial-balance"]);
+	assert_eq!(String::from_utf8_lossy(&output.stderr), "trial balance after all inputs: ok\n");
+
+	let (code, _) = run_status(&[a, "--threads", "2", "--trial-balance"]);
+	assert_eq!(code, Some(2));
+}
+
+#[test]
+fn paranoid() {
+	let input = fixture("tests/fixtures/schema.csv");
+	let input = input.to_str().unwrap();
+	let output = run_with(&[input, "--paranoid", "--disputes", "by-type"]);
+	assert_eq!(lines(&output), lines(&run_with(&[input, "--disputes", "by-type"])));
+	assert!(output.stderr.is_empty());
+
+	let (code, _) = run_status(&[input, "--threads", "2", "--paranoid"]);
+	assert_eq!(code, Some(2));
+}
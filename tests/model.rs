@@ -0,0 +1,389 @@
+//! Random tx sequences over many clients, applied to the `Store` and to a
+//! deliberately naive model of the spec, which have to agree on every tx
+//! and on the final state. Along the way the store has to keep its
+//! invariants: held funds are never negative, money is conserved, and
+//! resolved or charged back txs stay that way.
+//!
+//! A failing sequence is shrunk to a minimal one before it's reported,
+//! along with the seed that generated it.
+
+use std::collections::BTreeMap;
+
+use rust_decimal::Decimal;
+
+use kraken::{
+	AccountSummary, ClientId, DisputePolicy, DisputeState, Outcome, Store, Transaction, TxId, TxType,
+};
+
+/// How many sequences are tried for each dispute policy
+const CASES: u64 = 150;
+/// How many txs a sequence has
+const TXS: usize = 250;
+
+/// A linear congruential generator, good enough to make up txs
+struct Rng(u64);
+
+impl Rng {
+	fn next(&mut self, n: u64) -> u64 {
+		self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+		(self.0 >> 33) % n
+	}
+
+	fn chance(&mut self, percent: u64) -> bool {
+		self.next(100) < percent
+	}
+}
+
+/// Made up txs that hit every rule often: most disputes reference a txid
+/// that was used before, mostly by the right client, and some amounts are
+/// invalid
+fn random_txs(seed: u64) -> Vec<Transaction> {
+	let mut rng = Rng(seed);
+	let mut used = Vec::<(TxId, ClientId)>::new();
+	(0..TXS)
+		.map(|_| {
+			let client = rng.next(20) as ClientId;
+			let kind = rng.next(10);
+			if kind < 6 || used.is_empty() {
+				// reused txids now and then
+				let txid = if rng.chance(5) && !used.is_empty() {
+					used[rng.next(used.len() as u64) as usize].0
+				} else {
+					used.len() as TxId + 1000
+				};
+				used.push((txid, client));
+				let amount = match rng.next(40) {
+					0 => Decimal::ZERO,
+					1 => Decimal::new(-(rng.next(1000) as i64) - 1, 2),
+					2 => Decimal::new(rng.next(100_000) as i64 * 10 + 1, 5),
+					_ => Decimal::new(rng.next(100_000) as i64 + 1, rng.next(5) as u32),
+				};
+				if kind < 4 {
+					Transaction::Deposit { txid, client, amount }
+				} else {
+					Transaction::Withdrawal { txid, client, amount }
+				}
+			} else {
+				let (txid, owner) = if rng.chance(90) {
+					used[rng.next(used.len() as u64) as usize]
+				} else {
+					(rng.next(5000) as TxId, client)
+				};
+				let client = if rng.chance(85) { owner } else { client };
+				match kind {
+					6..=7 => Transaction::Dispute { client, txid },
+					8 => Transaction::Resolve { client, txid },
+					_ => Transaction::Chargeback { client, txid },
+				}
+			}
+		})
+		.collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ModelAccount {
+	available: Decimal,
+	held: Decimal,
+	locked: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ModelTx {
+	txid: TxId,
+	client: ClientId,
+	amount: Decimal,
+	withdrawal: bool,
+	state: DisputeState,
+}
+
+/// The spec, with the default policies and the given dispute policy,
+/// written as plainly as possible: a list of txs searched from the start
+/// every time, and every rule checked right where it's needed
+struct Model {
+	by_type: bool,
+	accounts: BTreeMap<ClientId, ModelAccount>,
+	txs: Vec<ModelTx>,
+}
+
+impl Model {
+	fn new(policy: DisputePolicy) -> Model {
+		Model {
+			by_type: policy == DisputePolicy::ByTxType,
+			accounts: BTreeMap::new(),
+			txs: Vec::new(),
+		}
+	}
+
+	fn account(&mut self, client: ClientId) -> &mut ModelAccount {
+		self.accounts.entry(client).or_insert(ModelAccount {
+			available: Decimal::ZERO,
+			held: Decimal::ZERO,
+			locked: false,
+		})
+	}
+
+	fn is_locked(&self, client: ClientId) -> bool {
+		self.accounts.get(&client).is_some_and(|a| a.locked)
+	}
+
+	fn find(&self, txid: TxId) -> Option<usize> {
+		self.txs.iter().position(|tx| tx.txid == txid)
+	}
+
+	/// Whether the tx is accepted
+	fn apply(&mut self, tx: Transaction) -> bool {
+		match tx {
+			Transaction::Deposit { txid, client, amount } | Transaction::Withdrawal { txid, client, amount } => {
+				let withdrawal = tx.tx_type() == TxType::Withdrawal;
+				if amount <= Decimal::ZERO || amount.normalize().scale() > 4 {
+					return false;
+				}
+				if self.find(txid).is_some() || self.is_locked(client) {
+					return false;
+				}
+				let account = self.account(client);
+				if withdrawal {
+					if account.available < amount {
+						return false;
+					}
+					account.available -= amount;
+				} else {
+					account.available += amount;
+				}
+				self.txs.push(ModelTx { txid, client, amount, withdrawal, state: DisputeState::Normal });
+				true
+			},
+			Transaction::Dispute { client, txid }
+			| Transaction::Resolve { client, txid }
+			| Transaction::Chargeback { client, txid } => {
+				let i = match self.find(txid) {
+					Some(i) => i,
+					None => return false,
+				};
+				let disputed = self.txs[i];
+				if disputed.client != client {
+					return false;
+				}
+				// a locked account can only settle disputes
+				if self.is_locked(client) && tx.tx_type() == TxType::Dispute {
+					return false;
+				}
+				let provisional = self.by_type && disputed.withdrawal;
+				let amount = disputed.amount;
+				let account = self.account(client);
+				let state = match (tx.tx_type(), disputed.state) {
+					(TxType::Dispute, DisputeState::Normal) => {
+						if provisional {
+							account.held += amount;
+						} else {
+							if account.available < amount {
+								return false;
+							}
+							account.available -= amount;
+							account.held += amount;
+						}
+						DisputeState::Disputed
+					},
+					(TxType::Resolve, DisputeState::Disputed) => {
+						if !provisional {
+							account.available += amount;
+						}
+						account.held -= amount;
+						DisputeState::Resolved
+					},
+					(TxType::Chargeback, DisputeState::Disputed) => {
+						if provisional {
+							account.available += amount;
+						}
+						account.held -= amount;
+						account.locked = true;
+						DisputeState::ChargedBack
+					},
+					_ => return false,
+				};
+				self.txs[i].state = state;
+				true
+			},
+		}
+	}
+
+	/// The accounts that have funds or are locked, like the store lists them
+	fn accounts(&self) -> Vec<AccountSummary> {
+		self.accounts
+			.iter()
+			.map(|(&client, a)| AccountSummary {
+				client,
+				available: a.available,
+				held: a.held,
+				total: a.available + a.held,
+				locked: a.locked,
+			})
+			.filter(is_used)
+			.collect()
+	}
+}
+
+/// Accounts that were only touched by refused txs may or may not exist,
+/// depending on where the tx was refused
+fn is_used(account: &AccountSummary) -> bool {
+	!account.available.is_zero() || !account.held.is_zero() || account.locked
+}
+
+fn is_allowed(before: DisputeState, after: DisputeState) -> bool {
+	use DisputeState::*;
+	before == after || matches!((before, after), (Normal, Disputed) | (Disputed, Resolved) | (Disputed, ChargedBack))
+}
+
+/// The money that should be in the accounts according to the history:
+/// deposits minus withdrawals, minus what was charged back, plus
+/// provisional credits that are pending or were charged back
+fn expected_money(store: &Store, policy: DisputePolicy) -> Decimal {
+	store
+		.transactions()
+		.map(|tx| {
+			let provisional = policy == DisputePolicy::ByTxType && tx.tp == TxType::Withdrawal;
+			let moved = if tx.tp == TxType::Deposit { tx.amount } else { -tx.amount };
+			let settled = match tx.dispute_state {
+				DisputeState::Disputed | DisputeState::ChargedBack if provisional => tx.amount,
+				DisputeState::ChargedBack => -tx.amount,
+				_ => Decimal::ZERO,
+			};
+			moved + settled
+		})
+		.sum()
+}
+
+/// Run txs through the store and the model, and describe the first way
+/// they disagree or the store breaks an invariant
+fn check(txs: &[Transaction], policy: DisputePolicy) -> Result<(), String> {
+	compare(txs, policy, Model::new(policy))
+}
+
+fn compare(txs: &[Transaction], policy: DisputePolicy, mut model: Model) -> Result<(), String> {
+	let mut store = Store::new().with_dispute_policy(policy).with_journal();
+
+	for (i, &tx) in txs.iter().enumerate() {
+		let before = store.get_transaction(tx.txid()).map(|t| t.dispute_state);
+		let result = store.apply(tx);
+		let accepted = model.apply(tx);
+		if result.is_ok() != accepted {
+			return Err(format!("tx {} {:?}: store says {:?}, model says {}", i, tx, result, accepted));
+		}
+		if accepted && result != Ok(Outcome::Applied) {
+			return Err(format!("tx {} {:?}: {:?}", i, tx, result));
+		}
+
+		if let (Some(before), Some(after)) = (before, store.get_transaction(tx.txid())) {
+			if !is_allowed(before, after.dispute_state) {
+				return Err(format!("tx {} {:?}: went from {:?} to {:?}", i, tx, before, after.dispute_state));
+			}
+		}
+		if let Some(account) = store.list_accounts().find(|a| a.held.is_sign_negative() && !a.held.is_zero()) {
+			return Err(format!("tx {} {:?}: negative held {:?}", i, tx, account));
+		}
+		let money = store.list_accounts().map(|a| a.total).sum::<Decimal>();
+		if money != expected_money(&store, policy) {
+			return Err(format!("tx {} {:?}: {} in the accounts, {} expected", i, tx, money, expected_money(&store, policy)));
+		}
+		let violations = store.audit();
+		if !violations.is_empty() {
+			return Err(format!("tx {} {:?}: {:?}", i, tx, violations));
+		}
+		if !store.trial_balance().unwrap().is_balanced() {
+			return Err(format!("tx {} {:?}: {:?}", i, tx, store.trial_balance()));
+		}
+	}
+
+	let accounts = store.list_accounts().filter(is_used).collect::<Vec<_>>();
+	if accounts != model.accounts() {
+		return Err(format!("accounts differ:\nstore {:?}\nmodel {:?}", accounts, model.accounts()));
+	}
+	for tx in &model.txs {
+		let state = store.get_transaction(tx.txid).map(|t| t.dispute_state);
+		if state != Some(tx.state) {
+			return Err(format!("tx {}: store has {:?}, model {:?}", tx.txid, state, tx.state));
+		}
+	}
+	if store.transactions().count() != model.txs.len() {
+		return Err("the store has txs the model doesn't".to_string());
+	}
+	Ok(())
+}
+
+/// Drop txs as long as the check still fails, to get a sequence where
+/// every tx matters
+fn shrink(
+	mut txs: Vec<Transaction>,
+	check: impl Fn(&[Transaction]) -> Result<(), String>,
+) -> (Vec<Transaction>, String) {
+	let mut error = check(&txs).unwrap_err();
+	let mut i = 0;
+	while i < txs.len() {
+		let mut fewer = txs.clone();
+		fewer.remove(i);
+		match check(&fewer) {
+			Err(e) => {
+				txs = fewer;
+				error = e;
+			},
+			Ok(()) => i += 1,
+		}
+	}
+	(txs, error)
+}
+
+fn run(policy: DisputePolicy) {
+	for seed in 0..CASES {
+		let txs = random_txs(seed);
+		if check(&txs, policy).is_err() {
+			let (txs, error) = shrink(txs, |txs| check(txs, policy));
+			let txs = txs.iter().map(|tx| format!("  {:?}\n", tx)).collect::<String>();
+			panic!("seed {} with {:?} fails: {}\nshrunk to:\n{}", seed, policy, error, txs);
+		}
+	}
+}
+
+#[test]
+fn same_as_model() {
+	run(DisputePolicy::AsDeposit);
+}
+
+#[test]
+fn same_as_model_by_tx_type() {
+	run(DisputePolicy::ByTxType);
+}
+
+/// The sequences have to get through to every rule, not just be refused
+#[test]
+fn txs_reach_every_state() {
+	let mut accepted = BTreeMap::<&str, u64>::new();
+	for seed in 0..10 {
+		let mut store = Store::new();
+		for tx in random_txs(seed) {
+			if store.apply(tx).is_ok() {
+				*accepted.entry(tx.tx_type().name()).or_default() += 1;
+			}
+		}
+	}
+	for tp in &["deposit", "withdrawal", "dispute", "resolve", "chargeback"] {
+		assert!(accepted.get(tp).copied().unwrap_or(0) >= 10, "too few {}s accepted: {:?}", tp, accepted);
+	}
+}
+
+/// A model of the other dispute policy has to be caught, and shrunk down
+/// to the few txs that show the difference
+#[test]
+fn catches_and_shrinks_differences() {
+	let mismatch = |txs: &[Transaction]| compare(txs, DisputePolicy::ByTxType, Model::new(DisputePolicy::AsDeposit));
+	let txs = (0..CASES)
+		.map(random_txs)
+		.find(|txs| mismatch(txs).is_err())
+		.expect("the dispute policies never made a difference");
+	let (txs, _) = shrink(txs, mismatch);
+	// a deposit, a withdrawal of it and a dispute of the withdrawal
+	assert_eq!(txs.iter().map(|tx| tx.tx_type()).collect::<Vec<_>>(), [
+		TxType::Deposit,
+		TxType::Withdrawal,
+		TxType::Dispute,
+	]);
+}